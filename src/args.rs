@@ -1,5 +1,11 @@
 use std::{
-    borrow::Cow, env, error::Error, fmt::Display, fs, path::Path, process, str::FromStr,
+    borrow::Cow,
+    env,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::Path,
+    str::FromStr,
     time::Duration,
 };
 
@@ -7,16 +13,30 @@ use anyhow::{Context, Result, bail};
 use pico_args::Arguments;
 
 use crate::{
-    Args as MainArgs, constants, hls::Args as HlsArgs, http::Args as HttpArgs,
-    output::Args as OutputArgs,
+    constants, hls::Args as HlsArgs, http::Args as HttpArgs, output::Args as OutputArgs,
+    session::Args as MainArgs,
 };
 
+/// Returned instead of a [`Config`](crate::Config) for `-h`/`--help` and `-V`/`--version`.
+///
+/// Displays as the text that should be printed to stdout.
+#[derive(Debug)]
+pub struct HelpError(String);
+
+impl Error for HelpError {}
+
+impl Display for HelpError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 pub trait Parse {
     fn parse(&mut self, parser: &mut Parser) -> Result<()>;
 }
 
-pub fn parse() -> Result<(MainArgs, HttpArgs, HlsArgs, OutputArgs)> {
-    let mut parser = Parser::new()?;
+pub fn parse(args: Arguments) -> Result<(MainArgs, HttpArgs, HlsArgs, OutputArgs)> {
+    let mut parser = Parser::new(args)?;
 
     let mut main = MainArgs::default();
     let mut http = HttpArgs::default();
@@ -73,6 +93,10 @@ impl Parser {
         self.resolve(dst, arg, cfg_key, Self::opt_from_str)
     }
 
+    pub fn parse_free_opt(&mut self) -> Result<Option<String>> {
+        Ok(self.parser.opt_free_from_str()?)
+    }
+
     pub fn parse_free_required(&mut self) -> Result<String> {
         Ok(self.parser.free_from_str()?)
     }
@@ -92,6 +116,25 @@ impl Parser {
         Ok(self.resolve(dst, arg, key2, bool::from_str)?)
     }
 
+    //Counts repeated switches, every prefix of runs is a switch counted by its length
+    //(ie. "-vvv" accepts -v, -vv and -vvv)
+    pub fn parse_count(
+        &mut self,
+        dst: &mut u8,
+        runs: &'static str,
+        cfg_key: &'static str,
+    ) -> Result<()> {
+        let mut count = 0u8;
+        for len in 2..=runs.len() {
+            let weight = u8::try_from(len - 1).unwrap_or(u8::MAX);
+            while self.parser.contains(&runs[..len]) {
+                count = count.saturating_add(weight);
+            }
+        }
+
+        Ok(self.resolve(dst, (count > 0).then_some(count), cfg_key, u8::from_str)?)
+    }
+
     pub fn parse_fn<T>(
         &mut self,
         dst: &mut T,
@@ -113,6 +156,21 @@ impl Parser {
         self.resolve(dst, arg, cfg_key, f)
     }
 
+    pub fn parse_opt_or<T: FromStr>(
+        &mut self,
+        dst: &mut Option<T>,
+        key1: &'static str,
+        key2: &'static str,
+    ) -> Result<()>
+    where
+        <T as FromStr>::Err: Display + Send + Sync + Error + 'static,
+    {
+        let arg = self
+            .parser
+            .opt_value_from_fn([key1, key2], Self::opt_from_str)?;
+        self.resolve(dst, arg, key2, Self::opt_from_str)
+    }
+
     /* These types should eventually just be wrapped with a FromStr impl */
 
     pub fn parse_cow_string(
@@ -124,14 +182,16 @@ impl Parser {
         self.resolve(dst, arg, key, Self::cow_string_impl)
     }
 
-    pub fn parse_cow_string_cfg(
+    pub fn parse_cow_string_or(
         &mut self,
         dst: &mut Cow<'static, str>,
-        key: &'static str,
-        cfg_key: &'static str,
+        key1: &'static str,
+        key2: &'static str,
     ) -> Result<()> {
-        let arg = self.parser.opt_value_from_fn(key, Self::cow_string_impl)?;
-        self.resolve(dst, arg, cfg_key, Self::cow_string_impl)
+        let arg = self
+            .parser
+            .opt_value_from_fn([key1, key2], Self::cow_string_impl)?;
+        self.resolve(dst, arg, key2, Self::cow_string_impl)
     }
 
     pub fn parse_duration(&mut self, dst: &mut Duration, key: &'static str) -> Result<()> {
@@ -158,6 +218,33 @@ impl Parser {
         self.parse_fn_cfg(dst, key, cfg_key, Self::comma_list_impl)
     }
 
+    //Collects every occurrence, the config file can also repeat the key
+    pub fn parse_repeated<T>(
+        &mut self,
+        dst: &mut Vec<T>,
+        key: &'static str,
+        f: fn(_: &str) -> Result<T>,
+    ) -> Result<()> {
+        let args = self.parser.values_from_fn(key, f)?;
+        if !args.is_empty() {
+            *dst = args;
+        } else if let Some(cfg) = &self.config {
+            let key = key.trim_start_matches('-');
+            let vals = cfg
+                .lines()
+                .filter_map(|l| l.split_once('='))
+                .filter(|(k, _)| *k == key)
+                .map(|(_, v)| f(v))
+                .collect::<Result<Vec<_>>>()?;
+
+            if !vals.is_empty() {
+                *dst = vals;
+            }
+        }
+
+        Ok(())
+    }
+
     fn resolve<T, E>(
         &self,
         dst: &mut T,
@@ -233,16 +320,19 @@ impl Parser {
         Ok(constants::DEFAULT_CONFIG_PATH)
     }
 
-    fn new() -> Result<Self> {
-        let mut parser = Arguments::from_env();
+    fn new(mut parser: Arguments) -> Result<Self> {
         if parser.contains("-h") || parser.contains("--help") {
-            print!(include_str!("usage"));
-            process::exit(0);
+            #[allow(clippy::useless_format)] //unescapes the braces
+            return Err(HelpError(format!(include_str!("usage"))).into());
         }
 
         if parser.contains("-V") || parser.contains("--version") {
-            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),);
-            process::exit(0);
+            return Err(HelpError(format!(
+                "{} {}\n",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ))
+            .into());
         }
 
         Ok(Self {
@@ -9,5 +9,15 @@ pub const TWITCH_HLS_BASE: &str = "https://usher.ttvnw.net/api/channel/hls/";
 
 pub const KICK_CHANNELS_ENDPOINT: &str = "https://kick.com/api/v2/channels";
 
+pub const AUDIO_ONLY_QUALITY: &str = "audio_only";
+pub const MAX_QUALITIES: usize = 4;
+pub const PRINT_URLS_SEGMENTS: u64 = 3;
+pub const RECORD_QUEUE_DEPTH: usize = 16;
+pub const PLAYER_PACE_QUEUE_DEPTH: usize = 16;
+pub const PREFETCH_RESTORE_SUCCESSES: usize = 10;
+pub const MAX_ALT_HOSTS: usize = 4;
+pub const RESUME_STATE_SAVE_SECS: u64 = 5;
+pub const AUDIO_FALLBACK_FAILURES: usize = 5;
+
 pub const DEFAULT_CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
 pub const DEFAULT_CONFIG_PATH: &str = concat!(env!("CARGO_PKG_NAME"), "/config");
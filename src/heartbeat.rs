@@ -0,0 +1,174 @@
+use std::{
+    env, fs,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, ensure};
+use log::{debug, error, info};
+
+use crate::args::{Parse, Parser};
+
+#[derive(Default, Debug)]
+pub struct Args {
+    interval: Option<Duration>,
+    file: Option<String>,
+    systemd: bool,
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse_fn(&mut self.interval, "--heartbeat", |arg| {
+            Ok(Some(Duration::try_from_secs_f64(arg.parse()?)?))
+        })?;
+        ensure!(
+            self.interval.is_none_or(|i| !i.is_zero()),
+            "--heartbeat must be greater than 0"
+        );
+        parser.parse_opt(&mut self.file, "--heartbeat-file")?;
+        parser.parse_switch(&mut self.systemd, "--systemd")?;
+
+        Ok(())
+    }
+}
+
+//Periodically reports that the main loop is still making progress
+pub struct Heartbeat {
+    interval: Duration,
+    last: Instant,
+    file: Option<String>,
+    notify: Option<Notify>,
+}
+
+impl Heartbeat {
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub fn new(args: &Args) -> Option<Self> {
+        let notify = if args.systemd { Notify::new() } else { None };
+        if args.interval.is_none() && args.file.is_none() && notify.is_none() {
+            return None;
+        }
+
+        let interval = args
+            .interval
+            .or_else(|| notify.as_ref().and_then(|_| Notify::watchdog_interval()))
+            .unwrap_or(Self::DEFAULT_INTERVAL);
+
+        if let Some(notify) = &notify {
+            notify.send("READY=1");
+        }
+
+        debug!("Heartbeat interval: {interval:?}");
+        Some(Self {
+            interval,
+            last: Instant::now(),
+            file: args.file.clone(),
+            notify,
+        })
+    }
+
+    pub fn tick(&mut self, segments: u64, latency: Option<Duration>, paused: bool) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+        self.last = Instant::now();
+
+        let latency = latency.map_or_else(
+            || "unknown".to_owned(),
+            |l| format!("{:.1}s", l.as_secs_f64()),
+        );
+        let paused = if paused { ", paused" } else { "" };
+        info!("Heartbeat: {segments} segments downloaded, edge latency {latency}{paused}");
+
+        if let Some(path) = &self.file {
+            let line = format!(
+                "{} {segments} {latency}\n",
+                chrono::Local::now().to_rfc3339()
+            );
+            if let Err(e) = fs::write(path, line) {
+                error!("Failed to write heartbeat file: {e}");
+            }
+        }
+
+        if let Some(notify) = &self.notify {
+            notify.send("WATCHDOG=1");
+        }
+    }
+}
+
+//sd_notify(3) without linking libsystemd
+struct Notify {
+    #[cfg(unix)]
+    sock: std::os::unix::net::UnixDatagram,
+    #[cfg(unix)]
+    path: String,
+}
+
+impl Notify {
+    #[cfg(unix)]
+    fn new() -> Option<Self> {
+        let Some(path) = env::var("NOTIFY_SOCKET").ok().filter(|p| !p.is_empty()) else {
+            debug!("NOTIFY_SOCKET not set, ignoring --systemd");
+            return None;
+        };
+
+        match std::os::unix::net::UnixDatagram::unbound() {
+            Ok(sock) => Some(Self { sock, path }),
+            Err(e) => {
+                error!("Failed to create systemd notify socket: {e}");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Option<Self> {
+        debug!("systemd notifications are unsupported on this platform, ignoring");
+        None
+    }
+
+    //Notify at half the watchdog timeout as recommended by sd_watchdog_enabled(3)
+    fn watchdog_interval() -> Option<Duration> {
+        env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .map(|usec| Duration::from_micros(usec) / 2)
+    }
+
+    #[cfg(unix)]
+    fn send(&self, state: &str) {
+        let result = self.path.strip_prefix('@').map_or_else(
+            || self.sock.send_to(state.as_bytes(), &self.path).map(|_| ()),
+            |name| Self::send_abstract(&self.sock, name, state),
+        );
+
+        if let Err(e) = result {
+            error!("Failed to notify systemd: {e}");
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, _state: &str) {}
+
+    #[cfg(target_os = "linux")]
+    fn send_abstract(
+        sock: &std::os::unix::net::UnixDatagram,
+        name: &str,
+        state: &str,
+    ) -> std::io::Result<()> {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let addr = SocketAddr::from_abstract_name(name)?;
+        sock.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn send_abstract(
+        _sock: &std::os::unix::net::UnixDatagram,
+        _name: &str,
+        _state: &str,
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::other(
+            "Abstract sockets are only supported on Linux",
+        ))
+    }
+}
@@ -1,21 +1,26 @@
 mod cache;
 mod multivariant;
 mod playlist;
+mod prefs;
+mod resume;
 mod segment;
 
-pub use multivariant::Stream;
+pub use multivariant::{Stream, Variant};
 pub use playlist::Playlist;
-pub use segment::{Handler, ResetError};
+pub use segment::{Handler, ResetError, VideoFailedError};
 
 use std::{
     borrow::Cow,
+    env,
     fmt::{self, Debug, Display, Formatter},
 };
 
 use anyhow::{Context, Result, bail, ensure};
+use log::info;
 
 use crate::{
     args::{Parse, Parser},
+    constants,
     http::{StatusError, Url},
 };
 
@@ -30,6 +35,18 @@ impl Display for OfflineError {
     }
 }
 
+#[derive(Debug)]
+pub struct PreflightError;
+
+impl std::error::Error for PreflightError {}
+
+impl Display for PreflightError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Preflight check failed, the CDN is unreachable")
+    }
+}
+
+#[derive(Clone)]
 pub struct Args {
     servers: Option<Vec<Url>>,
     print_streams: bool,
@@ -37,14 +54,26 @@ pub struct Args {
     passthrough: Passthrough,
     client_id: Option<String>,
     auth_token: Option<String>,
+    integrity_token: Option<String>,
     codecs: Cow<'static, str>,
     never_proxy: Option<Vec<String>>,
     playlist_cache_dir: Option<String>,
+    prefs: Option<String>,
     use_cache_only: bool,
     write_cache_only: bool,
     force_playlist_url: Option<Url>,
+    playlist_url: Option<Url>,
     pub channel: String,
     quality: Option<String>,
+    strict_quality: bool,
+    prefer_video_else_audio: bool,
+    min_quality: Option<MinQuality>,
+    require_min_quality: bool,
+    start_at: Option<f64>,
+    pub once: bool,
+    pub preflight: bool,
+    print_urls: UrlPrinter,
+    pub handler: segment::Args,
 }
 
 impl Default for Args {
@@ -57,13 +86,25 @@ impl Default for Args {
             passthrough: Passthrough::default(),
             client_id: Option::default(),
             auth_token: Option::default(),
+            integrity_token: Option::default(),
             never_proxy: Option::default(),
             playlist_cache_dir: Option::default(),
+            prefs: Option::default(),
             use_cache_only: bool::default(),
             write_cache_only: bool::default(),
             force_playlist_url: Option::default(),
+            playlist_url: Option::default(),
             channel: String::default(),
             quality: Option::default(),
+            strict_quality: bool::default(),
+            prefer_video_else_audio: bool::default(),
+            min_quality: Option::default(),
+            require_min_quality: bool::default(),
+            start_at: Option::default(),
+            once: bool::default(),
+            preflight: bool::default(),
+            print_urls: UrlPrinter::default(),
+            handler: segment::Args::default(),
         }
     }
 }
@@ -84,32 +125,82 @@ impl Debug for Args {
             .field("passthrough", &self.passthrough)
             .field("client_id", &hide_option(&self.client_id))
             .field("auth_token", &hide_option(&self.auth_token))
+            .field("integrity_token", &hide_option(&self.integrity_token))
             .field("codecs", &self.codecs)
             .field("never_proxy", &self.never_proxy)
             .field("playlist_cache_dir", &self.playlist_cache_dir)
+            .field("prefs", &self.prefs)
             .field("use_cache_only", &self.use_cache_only)
             .field("write_cache_only", &self.write_cache_only)
             .field("force_playlist_url", &self.force_playlist_url)
+            .field("playlist_url", &self.playlist_url)
             .field("channel", &self.channel)
             .field("quality", &self.quality)
+            .field("strict_quality", &self.strict_quality)
+            .field("prefer_video_else_audio", &self.prefer_video_else_audio)
+            .field("min_quality", &self.min_quality)
+            .field("require_min_quality", &self.require_min_quality)
+            .field("start_at", &self.start_at)
+            .field("once", &self.once)
+            .field("preflight", &self.preflight)
+            .field("print_urls", &self.print_urls)
+            .field("handler", &self.handler)
             .finish()
     }
 }
 
 impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        self.handler.parse(parser)?;
         parser.parse_comma_list_cfg(&mut self.servers, "-s", "servers")?;
         parser.parse_switch(&mut self.print_streams, "--print-streams")?;
         parser.parse_switch(&mut self.no_low_latency, "--no-low-latency")?;
         parser.parse_fn(&mut self.passthrough, "--passthrough", Passthrough::new)?;
         parser.parse_opt(&mut self.client_id, "--client-id")?;
         parser.parse_opt(&mut self.auth_token, "--auth-token")?;
+        parser.parse_opt(&mut self.integrity_token, "--integrity-token")?;
+
+        //Kept out of the command line and config file, where other users or backups can see them
+        Self::from_env(&mut self.client_id, "TWITCH_CLIENT_ID");
+        Self::from_env(&mut self.integrity_token, "TWITCH_CLIENT_INTEGRITY");
         parser.parse_cow_string(&mut self.codecs, "--codecs")?;
         parser.parse_comma_list(&mut self.never_proxy, "--never-proxy")?;
         parser.parse_opt(&mut self.playlist_cache_dir, "--playlist-cache-dir")?;
+        parser.parse_opt(&mut self.prefs, "--prefs")?;
         parser.parse_switch(&mut self.use_cache_only, "--use-cache-only")?;
         parser.parse_switch(&mut self.write_cache_only, "--write-cache-only")?;
         parser.parse_opt(&mut self.force_playlist_url, "--force-playlist-url")?;
+        parser.parse_opt(&mut self.playlist_url, "--playlist-url")?;
+        parser.parse_switch(&mut self.strict_quality, "--strict-quality")?;
+        parser.parse_switch(
+            &mut self.prefer_video_else_audio,
+            "--prefer-video-else-audio",
+        )?;
+        parser.parse_fn(&mut self.min_quality, "--min-quality", MinQuality::new)?;
+        parser.parse_switch(&mut self.require_min_quality, "--require-min-quality")?;
+        ensure!(
+            !self.require_min_quality || self.min_quality.is_some(),
+            "--require-min-quality requires --min-quality"
+        );
+
+        parser.parse_opt(&mut self.start_at, "--start-at")?;
+        ensure!(
+            self.start_at.is_none_or(f64::is_finite),
+            "Invalid --start-at offset"
+        );
+
+        //Whole playlist unless told otherwise
+        parser.parse_switch(&mut self.once, "--once")?;
+        if self.once && self.start_at.is_none() {
+            self.start_at = Some(0.0);
+        }
+
+        parser.parse_switch(&mut self.preflight, "--preflight")?;
+
+        parser.parse_switch(&mut self.print_urls.enabled, "--print-urls")?;
+        parser.parse_switch(&mut self.print_urls.show_tokens, "--show-tokens")?;
+        self.handler.print_urls = self.print_urls;
+        self.handler.audio_fallback = self.prefer_video_else_audio;
 
         if self.use_cache_only || self.write_cache_only {
             ensure!(
@@ -123,20 +214,48 @@ impl Parse for Args {
             "--use-cache-only and --write-cache-only cannot be used together"
         );
 
-        let channel = parser
-            .parse_free_required()
-            .context("Missing channel argument")?;
+        ensure!(
+            self.playlist_url.is_none() || self.force_playlist_url.is_none(),
+            "--playlist-url and --force-playlist-url cannot be used together"
+        );
 
+        let channel = self.parse_free_args(parser)?;
         self.channel = channel
             .rsplit_once('/')
             .map_or(channel.as_str(), |s| s.1)
             .to_lowercase();
 
-        parser.parse_free(&mut self.quality, "quality")?;
         if self.print_streams {
             self.quality = None;
         }
 
+        if let Some(quality) = &self.quality
+            && quality.contains('+')
+        {
+            let count = quality.split('+').count();
+            ensure!(
+                count <= constants::MAX_QUALITIES,
+                "Can't record more than {} qualities at once",
+                constants::MAX_QUALITIES
+            );
+            ensure!(
+                quality.split('+').all(|q| !q.is_empty()),
+                "Invalid quality list"
+            );
+            ensure!(
+                self.playlist_url.is_none() && self.force_playlist_url.is_none(),
+                "Multiple qualities cannot be used with --playlist-url or --force-playlist-url"
+            );
+            ensure!(
+                matches!(self.passthrough, Passthrough::Disabled),
+                "Multiple qualities cannot be used with --passthrough"
+            );
+            ensure!(
+                self.handler.resume_state.is_none(),
+                "Multiple qualities cannot be used with --resume-state"
+            );
+        }
+
         if let Some(never_proxy) = &self.never_proxy
             && never_proxy.iter().any(|a| a.eq(&self.channel))
         {
@@ -147,7 +266,85 @@ impl Parse for Args {
     }
 }
 
-#[derive(Debug, Default)]
+impl Args {
+    //One set of args per quality separated by '+', each may still be a fallback list
+    pub fn split_qualities(&self) -> Vec<Self> {
+        let Some(quality) = &self.quality else {
+            return vec![self.clone()];
+        };
+
+        quality
+            .split('+')
+            .map(|q| Self {
+                quality: Some(q.to_owned()),
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    pub fn fall_back_to_audio(&mut self) {
+        self.quality = Some(constants::AUDIO_ONLY_QUALITY.to_owned());
+    }
+
+    //The channel only names the output with --playlist-url, a lone argument is the quality
+    fn parse_free_args(&mut self, parser: &mut Parser) -> Result<String> {
+        let Some(url) = &self.playlist_url else {
+            let channel = parser
+                .parse_free_required()
+                .context("Missing channel argument")?;
+
+            parser.parse_free(&mut self.quality, "quality")?;
+            return Ok(channel);
+        };
+
+        let (channel, quality) = match parser.parse_free_opt()? {
+            Some(first) => match parser.parse_free_opt()? {
+                Some(quality) => (first, Some(quality)),
+                None => (Self::name_from_url(url), Some(first)),
+            },
+            None => (Self::name_from_url(url), None),
+        };
+
+        match quality {
+            Some(quality) => self.quality = Some(quality),
+            None => parser.parse_free(&mut self.quality, "quality")?,
+        }
+
+        Ok(channel)
+    }
+
+    //Playlist filename without the extension, ie. index for https://host/live/index.m3u8
+    fn name_from_url(url: &Url) -> String {
+        let file = url
+            .path()
+            .unwrap_or_default()
+            .split(['?', '#'])
+            .next()
+            .and_then(|p| p.rsplit('/').next())
+            .unwrap_or_default();
+
+        let name = file
+            .split_once('.')
+            .map_or(file, |s| s.0)
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+            .collect::<String>();
+
+        if name.is_empty() {
+            "playlist".to_owned()
+        } else {
+            name
+        }
+    }
+
+    fn from_env(arg: &mut Option<String>, var: &str) {
+        if arg.is_none() {
+            *arg = env::var(var).ok().filter(|v| !v.is_empty());
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 enum Passthrough {
     Variant,
     Multivariant,
@@ -167,6 +364,71 @@ impl Passthrough {
     }
 }
 
+//Lowest acceptable quality, ie. 720p or 720p60
+#[derive(Debug, Copy, Clone)]
+struct MinQuality {
+    height: u16,
+    frame_rate: Option<u16>,
+}
+
+impl Display for MinQuality {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}p", self.height)?;
+        if let Some(frame_rate) = self.frame_rate {
+            write!(f, "{frame_rate}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MinQuality {
+    fn new(arg: &str) -> Result<Option<Self>> {
+        let (height, frame_rate) = arg
+            .split_once('p')
+            .context("Invalid minimum quality, must be like 720p or 720p60")?;
+
+        Ok(Some(Self {
+            height: height.parse().context("Invalid minimum quality height")?,
+            frame_rate: match frame_rate {
+                "" => None,
+                frame_rate => Some(
+                    frame_rate
+                        .parse()
+                        .context("Invalid minimum quality frame rate")?,
+                ),
+            },
+        }))
+    }
+
+    //Frame rate is only compared at the same height
+    fn allows(self, height: u16, frame_rate: u16) -> bool {
+        height > self.height
+            || height == self.height && self.frame_rate.is_none_or(|f| frame_rate >= f)
+    }
+}
+
+//Logs resolved URLs for --print-urls, redacted unless --show-tokens is set
+#[derive(Debug, Default, Copy, Clone)]
+struct UrlPrinter {
+    enabled: bool,
+    show_tokens: bool,
+}
+
+impl UrlPrinter {
+    fn print(self, name: &str, url: &Url) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.show_tokens {
+            info!("{name} URL: {url}");
+        } else {
+            info!("{name} URL: {}", url.redacted());
+        }
+    }
+}
+
 fn map_if_offline(error: anyhow::Error) -> anyhow::Error {
     if StatusError::is_not_found(&error) {
         return OfflineError.into();
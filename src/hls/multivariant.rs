@@ -5,31 +5,135 @@ use std::{
     str::{self, Utf8Error},
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, bail, ensure};
 use getrandom::getrandom;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-use super::{Args, OfflineError, Passthrough, cache::Cache, map_if_offline};
+use super::{
+    Args, MinQuality, OfflineError, Passthrough, cache::Cache, map_if_offline, prefs::Prefs,
+};
 
 use crate::{
     constants,
-    http::{Agent, Connection, Method, StatusError, Url},
+    http::{Agent, Connection, Method, StatusError, TextRequest, Url},
 };
 
+#[allow(clippy::large_enum_variant)] //rarely constructed
 pub enum Stream {
-    Variant(Connection),
+    Variant(Connection, Option<Variant>),
     Passthrough(Url),
     Exit,
 }
 
+//Multivariant playlist used to switch qualities if the current one disappears
+pub struct Variant {
+    url: Url,
+    qualities: Vec<String>,
+    name: String,
+    strict: bool,
+    audio_fallback: bool,
+}
+
+impl Variant {
+    //Returns the new variant playlist URL if the current quality is gone
+    pub fn reselect(&mut self, request: &mut TextRequest) -> Result<Option<Url>> {
+        request
+            .text(Method::Get, &self.url)
+            .map_err(map_if_offline)?;
+
+        self.reselect_from(&request.take())
+    }
+
+    //Checked on every reload, so a failed request only skips this check
+    pub fn check(&mut self, request: &mut TextRequest) -> Result<Option<Url>> {
+        match request.text_no_retry(Method::Get, &self.url) {
+            Ok(()) => self.reselect_from(&request.take()),
+            Err(e) if StatusError::is_not_found(&e) => Err(OfflineError.into()),
+            Err(e) => {
+                debug!("Failed to check multivariant playlist: {e}");
+                Ok(None)
+            }
+        }
+    }
+
+    fn reselect_from(&mut self, playlist: &str) -> Result<Option<Url>> {
+        if playlist_iter(playlist).any(|it| it.name == self.name) {
+            return Ok(None);
+        }
+
+        ensure!(
+            !self.strict,
+            "Quality {} is no longer available and --strict-quality is enabled",
+            self.name,
+        );
+
+        let (name, url) = choose_stream_or_audio(playlist, &self.qualities, self.audio_fallback)
+            .context("None of the requested qualities are available anymore")?;
+
+        info!(
+            "Quality {} is no longer available, switching to {name}",
+            self.name
+        );
+        self.name = name;
+
+        Ok(Some(url))
+    }
+
+    //Returns the audio only playlist URL, if there is one
+    pub fn audio_only(&mut self, request: &mut TextRequest) -> Result<Option<Url>> {
+        let playlist = request
+            .text(Method::Get, &self.url)
+            .map_err(map_if_offline)?;
+
+        let quality = [constants::AUDIO_ONLY_QUALITY.to_owned()];
+        let Some((name, url)) = choose_stream(playlist, &quality) else {
+            return Ok(None);
+        };
+
+        info!(
+            "Segments of {} keep failing, falling back to {name}",
+            self.name
+        );
+        self.name = name;
+
+        Ok(Some(url))
+    }
+
+    //Returns the media playlist URL of the current quality, which can move to another host
+    pub fn resolve(&self, request: &mut TextRequest) -> Result<Option<Url>> {
+        let playlist = request
+            .text(Method::Get, &self.url)
+            .map_err(map_if_offline)?;
+
+        Ok(playlist_iter(playlist)
+            .find(|it| it.name == self.name)
+            .map(|it| it.url.into()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 impl Stream {
     pub fn new(mut args: Args, agent: &Agent) -> Result<Self> {
         if let Some(url) = args.force_playlist_url.take() {
             info!("Using forced playlist URL");
-            return Ok(Self::Variant(Connection::new(url, agent.text())));
+            args.print_urls.print("Media playlist", &url);
+            return Ok(Self::Variant(Connection::new(url, agent.text()), None));
+        }
+
+        let prefs = Prefs::new(&args.prefs);
+        if args.quality.is_none()
+            && !args.print_streams
+            && let Some(prefs) = &prefs
+        {
+            args.quality = Some(prefs.quality(&args.channel));
         }
 
-        let cache = Cache::new(&args.playlist_cache_dir, &args.channel, &args.quality);
+        let playlist_url = args.playlist_url.take();
+        let cache = Cache::new(&args.playlist_cache_dir, &args.channel, &args.quality)
+            .filter(|_| playlist_url.is_none());
         if let Some(conn) = cache.as_ref().and_then(|c| c.get(agent)) {
             if args.write_cache_only {
                 info!("Playlist URL is already cached, exiting...");
@@ -37,45 +141,65 @@ impl Stream {
             }
 
             info!("Using cached playlist URL");
-            return Ok(Self::Variant(conn));
+            args.print_urls.print("Media playlist", &conn.url);
+            return Ok(Self::Variant(conn, None));
         } else if args.use_cache_only {
             bail!("Playlist URL not found in cache");
         }
 
-        info!("Fetching playlist for channel {}", &args.channel);
-        let (multivariant_url, playlist) =
-            if let Some(channel) = &args.channel.strip_prefix("kick:") {
-                fetch_kick_playlist(channel, agent)?
-            } else if let Some(servers) = &args.servers {
-                fetch_proxy_playlist(
-                    !args.no_low_latency,
-                    servers,
-                    &args.codecs,
-                    &args.channel,
-                    agent,
-                )?
-            } else {
-                let response = fetch_twitch_gql(
-                    args.client_id.take(),
-                    args.auth_token.take(),
-                    &args.channel,
-                    agent,
-                )?;
-
-                fetch_twitch_playlist(
-                    &response,
-                    !args.no_low_latency,
-                    &args.codecs,
-                    &args.channel,
-                    agent,
-                )?
-            };
-
-        let Some(url) = choose_stream(&playlist, &args.quality, args.print_streams) else {
+        let is_kick = args.channel.starts_with("kick:") && playlist_url.is_none();
+        let (multivariant_url, playlist) = if let Some(url) = playlist_url {
+            info!("Using playlist URL");
+
+            let mut request = agent.text();
+            request.text(Method::Get, &url).map_err(map_if_offline)?;
+
+            let playlist = request.take();
+            if !playlist.contains("#EXT-X-STREAM-INF") {
+                debug!("Playlist URL is a media playlist");
+                args.print_urls.print("Media playlist", &url);
+                return match args.passthrough {
+                    Passthrough::Disabled => Ok(Self::Variant(Connection::new(url, request), None)),
+                    Passthrough::Variant | Passthrough::Multivariant => Ok(Self::Passthrough(url)),
+                };
+            }
+
+            (url, playlist)
+        } else {
+            fetch_channel_playlist(&mut args, agent)?
+        };
+
+        debug!("Multivariant playlist:\n{playlist}");
+        args.print_urls.print("Master playlist", &multivariant_url);
+        let qualities = match &args.quality {
+            Some(quality) if !args.print_streams => {
+                quality.split(',').map(str::to_owned).collect::<Vec<_>>()
+            }
+            _ => {
+                print_streams(&playlist);
+                return Ok(Self::Exit);
+            }
+        };
+
+        if let Some(min_quality) = args.min_quality {
+            check_min_quality(&playlist, min_quality, args.require_min_quality)?;
+        }
+
+        let Some((name, url)) =
+            choose_stream_or_audio(&playlist, &qualities, args.prefer_video_else_audio)
+        else {
             print_streams(&playlist);
             return Ok(Self::Exit);
         };
 
+        //Audio fallbacks aren't what was asked for
+        if let Some(prefs) = &prefs
+            && qualities.iter().any(|q| *q == name || q == "best")
+        {
+            prefs.set(&args.channel, &name);
+        }
+
+        args.print_urls.print("Media playlist", &url);
         if let Some(cache) = &cache {
             cache.create(&url);
 
@@ -86,21 +210,72 @@ impl Stream {
         }
 
         match args.passthrough {
-            Passthrough::Disabled => Ok(Self::Variant(Connection::new(url, agent.text()))),
+            Passthrough::Disabled => Ok(Self::Variant(
+                Connection::new(url, agent.text()),
+                //Kick's multivariant URL is an API endpoint, not a playlist
+                (!is_kick).then_some(Variant {
+                    url: multivariant_url,
+                    qualities,
+                    name,
+                    strict: args.strict_quality,
+                    audio_fallback: args.prefer_video_else_audio,
+                }),
+            )),
             Passthrough::Variant => Ok(Self::Passthrough(url)),
             Passthrough::Multivariant => Ok(Self::Passthrough(multivariant_url)),
         }
     }
 }
 
+fn fetch_channel_playlist(args: &mut Args, agent: &Agent) -> Result<(Url, String)> {
+    info!("Fetching playlist for channel {}", &args.channel);
+    if let Some(channel) = &args.channel.strip_prefix("kick:") {
+        fetch_kick_playlist(channel, agent)
+    } else if let Some(servers) = &args.servers {
+        Ok(fetch_proxy_playlist(
+            !args.no_low_latency,
+            servers,
+            &args.codecs,
+            &args.channel,
+            agent,
+        )?)
+    } else {
+        let response = fetch_twitch_gql(
+            args.client_id.take(),
+            args.auth_token.take(),
+            args.integrity_token.take(),
+            &args.channel,
+            agent,
+        )?;
+
+        fetch_twitch_playlist(
+            &response,
+            !args.no_low_latency,
+            &args.codecs,
+            &args.channel,
+            agent,
+        )
+    }
+}
+
 fn fetch_twitch_gql(
     client_id: Option<String>,
     auth_token: Option<String>,
+    integrity_token: Option<String>,
     channel: &str,
     agent: &Agent,
 ) -> Result<String> {
     const GQL_LEN_WITHOUT_CHANNEL: usize = 267;
 
+    //Integrity tokens are only valid for the client ID that generated them
+    match (&client_id, &integrity_token) {
+        (Some(_), None) => warn!("--client-id is set without --integrity-token"),
+        (None, Some(_)) if auth_token.is_none() => {
+            warn!("--integrity-token is set without --client-id, using the default client ID");
+        }
+        _ => (),
+    }
+
     let mut client_id_buf = ArrayString::<30>::new();
     let client_id = choose_client_id(&mut client_id_buf, client_id, &auth_token, agent)?;
 
@@ -113,6 +288,7 @@ fn fetch_twitch_gql(
              X-Device-ID: {device_id}\r\n\
              Client-ID: {client_id}\r\n\
              {auth_token_head}{auth_token}{auth_token_tail}\
+             {integrity_head}{integrity_token}{integrity_tail}\
              Content-Length: {content_length}\r\n\
              \r\n\
              {{\
@@ -137,6 +313,9 @@ fn fetch_twitch_gql(
              auth_token_head = if auth_token.is_some() { "Authorization: OAuth " } else { "" },
              auth_token_tail = if auth_token.is_some() { "\r\n" } else { "" },
              auth_token = auth_token.unwrap_or_default(),
+             integrity_head = if integrity_token.is_some() { "Client-Integrity: " } else { "" },
+             integrity_tail = if integrity_token.is_some() { "\r\n" } else { "" },
+             integrity_token = integrity_token.unwrap_or_default(),
         )
     )?;
 
@@ -275,6 +454,7 @@ struct PlaylistItem<'a> {
     name: &'a str,
     url: &'a str,
     resolution: Option<(u16, u16)>,
+    frame_rate: Option<u16>,
 }
 
 impl<'a> PlaylistItem<'a> {
@@ -298,10 +478,22 @@ impl<'a> PlaylistItem<'a> {
                 }
             });
 
+        let frame_rate = stream_inf
+            .split_once("FRAME-RATE=")
+            .and_then(|(_, tail)| tail.split(',').next())
+            .and_then(|frame_rate| {
+                //Rounded so 59.940 counts as 60
+                let (int, frac) = frame_rate.split_once('.').unwrap_or((frame_rate, ""));
+                let int = int.parse::<u16>().ok()?;
+
+                Some(int + u16::from(frac.starts_with(['5', '6', '7', '8', '9'])))
+            });
+
         Some(Self {
             name,
             url,
             resolution,
+            frame_rate,
         })
     }
 }
@@ -333,18 +525,67 @@ fn playlist_iter(playlist: &str) -> impl Iterator<Item = PlaylistItem<'_>> {
         .filter_map(|((media, url), stream_inf)| PlaylistItem::parse(media, stream_inf, url))
 }
 
-fn choose_stream(playlist: &str, quality: &Option<String>, should_print: bool) -> Option<Url> {
-    debug!("Multivariant playlist:\n{playlist}");
-    let (Some(quality), false) = (quality, should_print) else {
-        return None;
+//Picks the first available quality in order of preference
+fn choose_stream(playlist: &str, qualities: &[String]) -> Option<(String, Url)> {
+    qualities.iter().find_map(|quality| {
+        let mut iter = playlist_iter(playlist);
+        let item = if quality == "best" {
+            iter.max()
+        } else {
+            iter.find(|it| it.name == quality)
+        }?;
+
+        Some((item.name.to_owned(), item.url.into()))
+    })
+}
+
+fn choose_stream_or_audio(
+    playlist: &str,
+    qualities: &[String],
+    audio_fallback: bool,
+) -> Option<(String, Url)> {
+    choose_stream(playlist, qualities).or_else(|| {
+        if !audio_fallback {
+            return None;
+        }
+
+        let (name, url) = choose_stream(playlist, &[constants::AUDIO_ONLY_QUALITY.to_owned()])?;
+        info!("None of the requested qualities are available, falling back to {name}");
+
+        Some((name, url))
+    })
+}
+
+//Resolution and frame rate of the best variant, audio only counts as the lowest
+fn check_min_quality(playlist: &str, min_quality: MinQuality, require: bool) -> Result<()> {
+    let Some(best) = playlist_iter(playlist).max_by_key(|it| {
+        (
+            it.resolution.map(|(_, height)| height),
+            it.frame_rate.unwrap_or_default(),
+        )
+    }) else {
+        return Ok(());
     };
 
-    let mut iter = playlist_iter(playlist);
-    if quality == "best" {
-        return iter.max().map(|it| it.url.into());
+    let (height, frame_rate) = (
+        best.resolution.map_or(0, |(_, height)| height),
+        best.frame_rate.unwrap_or_default(),
+    );
+    if min_quality.allows(height, frame_rate) {
+        return Ok(());
     }
 
-    iter.find(|it| it.name == quality).map(|it| it.url.into())
+    ensure!(
+        !require,
+        "Best available quality {} is below --min-quality {min_quality}",
+        best.name
+    );
+
+    warn!(
+        "Best available quality {} is below --min-quality {min_quality}",
+        best.name
+    );
+    Ok(())
 }
 
 fn print_streams(playlist: &str) {
@@ -1,82 +1,170 @@
 use std::{
     collections::{VecDeque, vec_deque::IterMut},
-    env,
+    env, io,
+    time::Instant,
 };
 
 use anyhow::{Context, Result, ensure};
-use log::debug;
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+use log::{debug, info, warn};
 
 use super::{
-    OfflineError, map_if_offline,
+    Args, OfflineError, PreflightError, Variant, map_if_offline,
     segment::{Duration, Segment},
 };
 
 use crate::{
-    http::{Connection, Url},
+    http::{self, Agent, Connection, Method, StatusError, Url},
     logger,
 };
 
 pub enum QueueRange<'a> {
-    Partial(IterMut<'a, Segment>),
-    Back(Option<&'a mut Segment>),
+    Partial(usize, IterMut<'a, Segment>),
+    Back(usize, Option<&'a mut Segment>),
     Empty,
 }
 
 pub struct Playlist {
-    pub header: Option<Url>, //used for av1/hevc streams
+    header: Option<Url>, //used for av1/hevc streams
+    header_changed: bool,
 
     conn: Connection,
+    variant: Option<Variant>,
+    variant_changed: bool,
     segments: VecDeque<Segment>,
+    target_duration: Option<Duration>, //used when #EXTINF is malformed
     should_debug_log: bool,
 
     sequence: usize,
     added: usize,
+    deferred: Option<usize>, //first sequence left for the next reload
+    sequence_reset: bool,
+
+    start_at: Option<f64>, //overrides EXT-X-START
+    joined: bool,
+    join_index: Option<usize>,
+
+    edge_time: Option<DateTime<FixedOffset>>, //end of the newest segment
+    once: bool,                               //ended playlists are still read
+    ended: bool,                              //segments of the final playlist are still queued
+    refreshing: bool,
 }
 
 impl Playlist {
-    pub fn new(conn: Connection) -> Result<Self> {
+    pub fn new(conn: Connection, variant: Option<Variant>, args: &Args) -> Result<Self> {
         let mut playlist = Self {
             conn,
+            variant_changed: variant.is_some(),
+            variant,
             segments: VecDeque::with_capacity(16),
+            target_duration: Option::default(),
             should_debug_log: logger::is_debug() && env::var_os("DEBUG_NO_PLAYLIST").is_none(),
             header: Option::default(),
+            header_changed: bool::default(),
             sequence: usize::default(),
             added: usize::default(),
+            deferred: Option::default(),
+            sequence_reset: bool::default(),
+            start_at: args.start_at,
+            joined: bool::default(),
+            join_index: Option::default(),
+            edge_time: Option::default(),
+            once: args.once,
+            ended: bool::default(),
+            refreshing: bool::default(),
         };
 
-        playlist.reload()?;
+        //The multivariant playlist was just fetched to select the variant
+        playlist.reload_media()?;
+        if playlist.ended && args.handler.resume_state.is_some() {
+            //A finished VOD is read to the end, the resume state skips what was already written
+            playlist.join_index.get_or_insert(0);
+        } else {
+            ensure!(!playlist.ended, OfflineError);
+        }
+
         Ok(playlist)
     }
 
     pub fn reload(&mut self) -> Result<()> {
-        let playlist = self.conn.text().map_err(map_if_offline)?;
-        if self.should_debug_log {
-            debug!("Playlist:\n{playlist}");
+        if self.ended {
+            return Err(OfflineError.into());
         }
 
-        if playlist
-            .lines()
-            .next_back()
-            .is_some_and(|l| l.trim() == "#EXT-X-ENDLIST")
+        //The selected quality can disappear from the multivariant playlist at any time
+        if let Some(variant) = &mut self.variant
+            && let Some(url) = variant.check(&mut self.conn.request)?
         {
-            return Err(OfflineError.into());
+            self.switch_to(url);
         }
 
+        self.reload_media()
+    }
+
+    fn reload_media(&mut self) -> Result<()> {
+        //Relative URLs are resolved against the current media playlist URL
+        let base = self.conn.url.clone();
+        let playlist = match self.conn.text() {
+            Ok(playlist) => playlist,
+            Err(e) => return self.recover(e),
+        };
+        if self.should_debug_log {
+            debug!("Playlist:\n{playlist}");
+        }
+
+        //Segments added by the final playlist are processed before the next reload ends the stream
+        self.ended = !self.once
+            && playlist
+                .lines()
+                .next_back()
+                .is_some_and(|l| l.trim() == "#EXT-X-ENDLIST");
+
+        let mut start_offset = None;
         let mut prefetch_removed = Self::remove_prefetch(&mut self.segments);
         let mut prev_segment_count = self.segments.len();
         let mut total_segments = 0;
-        let mut lines = playlist.lines();
-        while let Some(line) = lines.next() {
+        let mut extinf = None;
+        for line in playlist.lines() {
+            //Every URI is a segment, even without #EXTINF or when it's malformed,
+            //so sequence numbers stay in sync
+            if !line.starts_with('#') && !line.trim().is_empty() {
+                total_segments += 1;
+                let extinf = extinf.take();
+                if total_segments > prev_segment_count {
+                    let duration =
+                        Self::segment_duration(extinf, line, &self.segments, self.target_duration);
+                    self.segments
+                        .push_back(if line.contains(char::is_whitespace) {
+                            Self::warn_malformed(line);
+                            Segment::Malformed(duration)
+                        } else {
+                            Segment::Normal(duration, base.join(line))
+                        });
+                }
+
+                continue;
+            }
+
             let Some(split) = line.split_once(':') else {
                 continue;
             };
 
             match split.0 {
                 "#EXT-X-MEDIA-SEQUENCE" => {
-                    let sequence = split.1.parse()?;
-                    ensure!(sequence >= self.sequence, "Sequence went backwards");
+                    let Ok(sequence) = split.1.parse() else {
+                        Self::warn_malformed(line);
+                        continue;
+                    };
+
+                    if sequence < self.sequence {
+                        debug!("Media sequence reset: {} -> {sequence}", self.sequence);
 
-                    if sequence > 0 {
+                        self.segments.clear();
+                        prev_segment_count = 0;
+                        prefetch_removed = 0;
+                        self.deferred = None;
+                        self.sequence_reset = true;
+                    } else if sequence > 0 {
                         let removed = sequence - self.sequence;
                         if removed < self.segments.len() {
                             self.segments.drain(..removed);
@@ -95,73 +183,600 @@ impl Playlist {
                     self.sequence = sequence;
                 }
                 "#EXT-X-MAP" if self.header.is_none() => {
-                    self.header = Some(
-                        split
-                            .1
-                            .split_once('=')
-                            .context("Failed to parse segment header")?
-                            .1
-                            .trim_matches('"')
-                            .into(),
-                    );
-                }
-                "#EXTINF" => {
-                    total_segments += 1;
-                    if total_segments > prev_segment_count
-                        && let Some(url) = lines.next()
-                    {
-                        self.segments
-                            .push_back(Segment::Normal(split.1.parse()?, url.into()));
-                    }
+                    let Some((_, uri)) = split.1.split_once('=') else {
+                        Self::warn_malformed(line);
+                        continue;
+                    };
+
+                    self.header_changed = true;
+                    self.header = Some(base.join(uri.trim_matches('"')));
                 }
+                "#EXT-X-START" if !self.joined => start_offset = Self::parse_start(split.1),
+                "#EXT-X-TARGETDURATION" => self.target_duration = Duration::from_target(split.1),
+                "#EXTINF" => extinf = Some(split.1),
                 "#EXT-X-TWITCH-PREFETCH" | "#EXT-X-PREFETCH" => {
                     total_segments += 1;
                     if total_segments > prev_segment_count {
-                        self.segments.push_back(Segment::Prefetch(split.1.into()));
+                        self.segments
+                            .push_back(Segment::Prefetch(base.join(split.1)));
                     }
                 }
                 _ => (),
             }
         }
 
+        if self.ended && total_segments == 0 {
+            return Err(OfflineError.into());
+        }
+
+        ensure!(total_segments > 0, "Playlist contains no usable segments");
+        self.edge_time = Self::parse_edge_time(playlist);
+
         self.added = total_segments - (prev_segment_count + prefetch_removed);
         debug!("Segments added: {}", self.added);
 
+        if !self.joined && !self.segments.is_empty() {
+            self.join(start_offset);
+        }
+
         Ok(())
     }
 
+    //Downloads the newest segment once without retries, so a broken network or proxy
+    //fails right away instead of after a round of retries in the download loop
+    pub fn preflight(&self, agent: &Agent) -> Result<()> {
+        let url = self
+            .segments
+            .iter()
+            .rev()
+            .find_map(|s| match s {
+                Segment::Normal(_, url) => Some(url),
+                Segment::Prefetch(_) | Segment::Malformed(_) => None,
+            })
+            .context("Playlist contains no segments to check")
+            .context(PreflightError)?;
+
+        let mut request = agent.binary(io::sink());
+        request.set_retries(0);
+
+        let start = Instant::now();
+        request.call(Method::Get, url).context(PreflightError)?;
+        info!(
+            "Preflight check passed, downloaded a segment from {} in {}ms",
+            url.host().unwrap_or_default(),
+            start.elapsed().as_millis()
+        );
+
+        Ok(())
+    }
+
+    //Returns the header URL if it changed since the last call
+    pub fn take_header(&mut self) -> Option<Url> {
+        if !self.header_changed {
+            return None;
+        }
+
+        self.header_changed = false;
+        self.header.clone()
+    }
+
+    //Joining a finished VOD only succeeds with --resume-state, otherwise it fails as offline
+    pub const fn has_ended(&self) -> bool {
+        self.ended
+    }
+
     pub fn reset(&mut self) {
         debug!("Resetting playlist...");
         self.segments.clear();
         self.sequence = 0;
         self.added = 0;
+        self.deferred = None;
     }
 
+    //Used when video segments keep failing, returns false if there's nothing to fall back to
+    pub fn fall_back_to_audio(&mut self) -> Result<bool> {
+        let Some(variant) = &mut self.variant else {
+            warn!("Segments keep failing, but there's no multivariant playlist to fall back with");
+            return Ok(false);
+        };
+
+        let Some(url) = variant.audio_only(&mut self.conn.request)? else {
+            warn!("Segments keep failing, but audio_only is not available");
+            return Ok(false);
+        };
+
+        self.switch_to(url);
+        Ok(true)
+    }
+
+    //Segments from `sequence` are queued again on the next reload, along with new ones
+    pub(super) const fn defer_from(&mut self, sequence: usize) {
+        self.deferred = Some(sequence);
+    }
+
+    //Media sequence numbers are paired with the first segment in the range
     pub(super) fn segment_queue(&mut self) -> QueueRange<'_> {
-        if self.added == 0 {
+        let len = self.segments.len();
+        let deferred = self
+            .deferred
+            .take()
+            .and_then(|sequence| sequence.checked_sub(self.sequence))
+            .filter(|index| *index < len - self.added);
+
+        if let Some(index) = self.join_index.take().or(deferred)
+            && index < len
+        {
+            QueueRange::Partial(self.sequence + index, self.segments.range_mut(index..))
+        } else if self.added == 0 {
             QueueRange::Empty
-        } else if self.added == self.segments.len() {
-            QueueRange::Back(self.segments.back_mut())
+        } else if self.added == len {
+            QueueRange::Back(self.sequence + len - 1, self.segments.back_mut())
         } else {
-            QueueRange::Partial(self.segments.range_mut(self.segments.len() - self.added..))
+            QueueRange::Partial(
+                self.sequence + len - self.added,
+                self.segments.range_mut(len - self.added..),
+            )
         }
     }
 
+    //Returns the quality name if it was selected or switched since the last call
+    pub(super) fn take_variant_change(&mut self) -> Option<String> {
+        if !self.variant_changed {
+            return None;
+        }
+
+        self.variant_changed = false;
+        self.variant.as_ref().map(|v| v.name().to_owned())
+    }
+
+    //Time between the end of the newest segment and now, requires #EXT-X-PROGRAM-DATE-TIME
+    pub fn edge_latency(&self) -> Option<std::time::Duration> {
+        self.edge_time
+            .and_then(|t| Utc::now().signed_duration_since(t).to_std().ok())
+    }
+
+    pub(super) const fn take_sequence_reset(&mut self) -> bool {
+        let reset = self.sequence_reset;
+        self.sequence_reset = false;
+
+        reset
+    }
+
     pub(super) fn last_duration(&self) -> Option<Duration> {
-        self.segments
+        Self::find_last_duration(&self.segments)
+    }
+
+    //Malformed or missing durations are estimated so media sequence numbers stay in sync
+    fn segment_duration(
+        extinf: Option<&str>,
+        uri: &str,
+        segments: &VecDeque<Segment>,
+        target_duration: Option<Duration>,
+    ) -> Duration {
+        if let Some(arg) = extinf {
+            if let Ok(duration) = arg.parse() {
+                return duration;
+            }
+
+            Self::warn_malformed(&format!("#EXTINF:{arg}"));
+        } else {
+            warn!("Segment without #EXTINF: {uri}");
+        }
+
+        Self::find_last_duration(segments)
+            .or(target_duration)
+            .unwrap_or(Duration::FALLBACK)
+    }
+
+    fn find_last_duration(segments: &VecDeque<Segment>) -> Option<Duration> {
+        segments
             .iter()
             .rev()
             .find_map(|s| match s {
-                Segment::Normal(duration, _) => Some(duration),
+                Segment::Normal(duration, _) | Segment::Malformed(duration) => Some(duration),
                 Segment::Prefetch(_) => None,
             })
             .copied()
     }
 
+    fn switch_variant(&mut self) -> Result<()> {
+        let variant = self
+            .variant
+            .as_mut()
+            .expect("Missing variant while switching");
+        let Some(url) = variant.reselect(&mut self.conn.request)? else {
+            return Err(OfflineError.into());
+        };
+
+        self.switch_to(url);
+        self.reload_media()
+    }
+
+    fn switch_to(&mut self, url: Url) {
+        self.conn.url = url;
+        self.variant_changed = true;
+        self.header = None;
+        self.reset();
+    }
+
+    fn recover(&mut self, error: anyhow::Error) -> Result<()> {
+        if self.variant.is_none() {
+            return Err(map_if_offline(error));
+        }
+
+        if StatusError::is_not_found(&error) {
+            self.switch_variant()
+        } else if !self.refreshing && http::is_transient_error(&error) {
+            self.refresh_url(error)
+        } else {
+            Err(error)
+        }
+    }
+
+    //The signed media playlist URL can move to another CDN host during long sessions,
+    //so errors that persist after retries re-resolve it from the multivariant playlist.
+    //Media sequence numbers carry over, so no discontinuity is needed.
+    fn refresh_url(&mut self, error: anyhow::Error) -> Result<()> {
+        let variant = self
+            .variant
+            .as_ref()
+            .expect("Missing variant while refreshing");
+        let url = match variant.resolve(&mut self.conn.request) {
+            Ok(Some(url)) => url,
+            Ok(None) => return self.switch_variant(),
+            Err(e) if e.is::<OfflineError>() => return Err(e),
+            Err(e) => {
+                debug!("Failed to refresh media playlist URL: {e}");
+                return Err(error);
+            }
+        };
+
+        if *url == *self.conn.url {
+            return Err(error);
+        }
+
+        match (self.conn.url.host(), url.host()) {
+            (Ok(old), Ok(new)) if old != new => info!("Media playlist moved from {old} to {new}"),
+            _ => info!("Media playlist URL changed, continuing with the new URL"),
+        }
+
+        self.conn.url = url;
+        self.refreshing = true;
+        let result = self.reload_media();
+        self.refreshing = false;
+
+        result
+    }
+
+    fn join(&mut self, start_offset: Option<f64>) {
+        self.joined = true;
+        if let Some(offset) = self.start_at.or(start_offset) {
+            let index = self.find_offset(offset);
+            debug!(
+                "Joining at segment {} for start offset {offset}",
+                self.sequence + index
+            );
+
+            self.join_index = Some(index);
+        }
+    }
+
+    //Newest program date time plus the duration of every segment after it
+    fn parse_edge_time(playlist: &str) -> Option<DateTime<FixedOffset>> {
+        let mut edge = None;
+        for line in playlist.lines() {
+            if let Some(date) = line.strip_prefix("#EXT-X-PROGRAM-DATE-TIME:") {
+                edge = DateTime::parse_from_rfc3339(date.trim()).ok();
+            } else if let Some(duration) = line.strip_prefix("#EXTINF:")
+                && let Some(time) = edge
+            {
+                edge = duration
+                    .parse::<Duration>()
+                    .ok()
+                    .and_then(|d| TimeDelta::from_std(d.inner()).ok())
+                    .map(|d| time + d);
+            }
+        }
+
+        edge
+    }
+
+    // #EXT-X-START:TIME-OFFSET=-12.5,PRECISE=YES
+    fn parse_start(attributes: &str) -> Option<f64> {
+        attributes
+            .split(',')
+            .find_map(|a| a.strip_prefix("TIME-OFFSET="))
+            .and_then(|o| o.parse::<f64>().ok())
+            .filter(|o| o.is_finite())
+    }
+
+    //Positive offsets are from the start of the playlist, negative from the end.
+    //Clamped to the available segments.
+    fn find_offset(&self, offset: f64) -> usize {
+        let durations = self.segments.iter().map(|s| match s {
+            Segment::Normal(duration, _) | Segment::Malformed(duration) => duration.as_secs_f64(),
+            Segment::Prefetch(_) => 0.0,
+        });
+
+        if offset >= 0.0 {
+            let mut elapsed = 0.0;
+            for (i, duration) in durations.enumerate() {
+                elapsed += duration;
+                if elapsed > offset {
+                    return i;
+                }
+            }
+
+            self.segments.len() - 1
+        } else {
+            let mut remaining = -offset;
+            for (i, duration) in durations.enumerate().rev() {
+                remaining -= duration;
+                if remaining <= 0.0 {
+                    return i;
+                }
+            }
+
+            0
+        }
+    }
+
+    fn warn_malformed(line: &str) {
+        warn!("Ignoring malformed playlist line: {line}");
+    }
+
     fn remove_prefetch(segments: &mut VecDeque<Segment>) -> usize {
         let before = segments.len();
-        segments.retain(|s| matches!(*s, Segment::Normal(_, _)));
+        segments.retain(|s| !matches!(*s, Segment::Prefetch(_)));
 
         before - segments.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        Compression,
+        write::{GzEncoder, ZlibEncoder},
+    };
+
+    use super::*;
+    use crate::http::mock::{self, Response, Server};
+
+    fn playlist(server: &Server) -> Result<Playlist> {
+        playlist_at(server, "/playlist.m3u8", &mock::agent())
+    }
+
+    fn playlist_at(server: &Server, path: &str, agent: &Agent) -> Result<Playlist> {
+        let conn = Connection::new(server.url(path), agent.text());
+        Playlist::new(conn, None, &Args::default())
+    }
+
+    //Same playlist at every path, compressed by the name of the path
+    fn compressed_server() -> Server {
+        Server::new(|path| {
+            let body = mock::media_playlist(3, &["http://x/s3", "http://x/s4"]);
+            let gzip = || -> std::io::Result<Vec<u8>> {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body.as_bytes())?;
+                encoder.finish()
+            };
+
+            match path {
+                "/gzip" => {
+                    Response::ok(gzip().expect("Failed to gzip")).header("Content-Encoding", "gzip")
+                }
+                "/chunked-gzip" => Response::ok(gzip().expect("Failed to gzip"))
+                    .header("Content-Encoding", "gzip")
+                    .chunked(),
+                "/deflate" => {
+                    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                    encoder
+                        .write_all(body.as_bytes())
+                        .expect("Failed to deflate");
+                    Response::ok(encoder.finish().expect("Failed to deflate"))
+                        .header("Content-Encoding", "deflate")
+                }
+                _ => Response::ok(body),
+            }
+        })
+    }
+
+    fn segments(playlist: &Playlist) -> Vec<(f64, String)> {
+        playlist
+            .segments
+            .iter()
+            .map(|s| match s {
+                Segment::Normal(duration, url) => (duration.as_secs_f64(), url.to_string()),
+                Segment::Prefetch(url) => (0.0, url.to_string()),
+                Segment::Malformed(duration) => (duration.as_secs_f64(), "malformed".to_owned()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn garbage_between_segments() -> Result<()> {
+        let (server, text) = Server::text();
+        mock::set(
+            &text,
+            "#EXTM3U\n\
+             #EXT-X-MEDIA-SEQUENCE:10\n\
+             #EXTINF:abc,live\n\
+             http://x/s10\n\
+             garbage ::: !!\n\
+             #EXTINF:1.000,live\n\
+             http://x/s11\n\
+             #EXT-X-MAP:nonsense\n\
+             #EXTINF:1.000,live\n\
+             http://x/s12\n",
+        );
+
+        //Malformed duration without a previous segment or target duration is still kept,
+        //and the malformed URI keeps its media sequence
+        let mut playlist = playlist(&server)?;
+        assert_eq!(playlist.sequence, 10);
+        assert_eq!(
+            segments(&playlist),
+            [
+                (2.0, "http://x/s10".to_owned()),
+                (2.0, "malformed".to_owned()),
+                (1.0, "http://x/s11".to_owned()),
+                (1.0, "http://x/s12".to_owned()),
+            ]
+        );
+        assert!(playlist.header.is_none());
+
+        mock::set(
+            &text,
+            "#EXTM3U\n\
+             #EXT-X-MEDIA-SEQUENCE:11\n\
+             garbage ::: !!\n\
+             #EXTINF:1.000,live\n\
+             http://x/s11\n\
+             #EXTINF:1.000,live\n\
+             http://x/s12\n\
+             #EXTINF:1.000,live\n\
+             http://x/s13\n",
+        );
+        playlist.reload()?;
+        assert_eq!(playlist.added, 1);
+        assert_eq!(
+            segments(&playlist).last(),
+            Some(&(1.0, "http://x/s13".to_owned()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn segment_without_extinf() -> Result<()> {
+        let (server, text) = Server::text();
+        mock::set(
+            &text,
+            "#EXTM3U\n\
+             #EXT-X-MEDIA-SEQUENCE:5\n\
+             #EXTINF:1.000,live\n\
+             http://x/s5\n\
+             http://x/s6\n\
+             #EXTINF:1.000,live\n\
+             #EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000Z\n\
+             http://x/s7\n",
+        );
+
+        //Bare URI gets the previous duration, tags between #EXTINF and its URI are skipped
+        let mut playlist = playlist(&server)?;
+        assert_eq!(
+            segments(&playlist),
+            [
+                (1.0, "http://x/s5".to_owned()),
+                (1.0, "http://x/s6".to_owned()),
+                (1.0, "http://x/s7".to_owned()),
+            ]
+        );
+
+        mock::set(
+            &text,
+            "#EXTM3U\n\
+             #EXT-X-MEDIA-SEQUENCE:6\n\
+             http://x/s6\n\
+             #EXTINF:1.000,live\n\
+             http://x/s7\n\
+             #EXTINF:1.000,live\n\
+             http://x/s8\n",
+        );
+
+        playlist.reload()?;
+        assert_eq!(playlist.sequence, 6);
+        assert_eq!(playlist.added, 1);
+        assert_eq!(
+            segments(&playlist),
+            [
+                (1.0, "http://x/s6".to_owned()),
+                (1.0, "http://x/s7".to_owned()),
+                (1.0, "http://x/s8".to_owned()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn final_playlist_is_parsed() -> Result<()> {
+        let (server, text) = Server::text();
+        mock::set(&text, &mock::media_playlist(0, &["http://x/s0"]));
+        let mut playlist = playlist(&server)?;
+
+        let ended = mock::media_playlist(0, &["http://x/s0", "http://x/s1"]) + "#EXT-X-ENDLIST\n";
+        mock::set(&text, &ended);
+        playlist.reload()?;
+        assert_eq!(playlist.added, 1);
+        assert!(matches!(
+            playlist.segment_queue(),
+            QueueRange::Partial(1, segments) if segments.len() == 1
+        ));
+
+        let error = playlist.reload().expect_err("Reloaded an ended playlist");
+        assert!(error.is::<OfflineError>());
+
+        //Already ended when joining
+        assert!(playlist_at(&server, "/", &mock::agent()).is_err_and(|e| e.is::<OfflineError>()));
+        Ok(())
+    }
+
+    #[test]
+    fn ended_playlist_is_resumed() -> Result<()> {
+        let (server, text) = Server::text();
+        let ended = mock::media_playlist(0, &["http://x/s0", "http://x/s1"]) + "#EXT-X-ENDLIST\n";
+        mock::set(&text, &ended);
+
+        let mut args = Args::default();
+        args.handler.resume_state = Some("unused".to_owned());
+        let conn = Connection::new(server.url("/playlist.m3u8"), mock::agent().text());
+        let mut playlist = Playlist::new(conn, None, &args)?;
+        assert!(matches!(
+            playlist.segment_queue(),
+            QueueRange::Partial(0, segments) if segments.len() == 2
+        ));
+        assert!(playlist.has_ended());
+
+        let error = playlist.reload().expect_err("Reloaded an ended playlist");
+        assert!(error.is::<OfflineError>());
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_playlists() -> Result<()> {
+        let server = compressed_server();
+        for path in ["/gzip", "/deflate", "/chunked-gzip"] {
+            let playlist = playlist_at(&server, path, &mock::agent())?;
+            assert_eq!(playlist.sequence, 3, "{path}");
+            assert_eq!(
+                segments(&playlist),
+                [
+                    (0.01, "http://x/s3".to_owned()),
+                    (0.01, "http://x/s4".to_owned()),
+                ],
+                "{path}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn playlist_over_max_size() {
+        let server = compressed_server();
+
+        //Limit applies to the decompressed size
+        let agent = mock::agent_with_playlist_size(32);
+        for path in ["/plain", "/gzip"] {
+            let error = playlist_at(&server, path, &agent)
+                .err()
+                .expect("Oversized playlist was parsed");
+            assert!(
+                error.to_string().contains("--max-playlist-size"),
+                "{path}: {error}"
+            );
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fs, io, path::PathBuf};
+
+use log::{debug, error, info};
+
+//Last selected quality per channel, one "channel=quality" line each
+pub struct Prefs {
+    path: PathBuf,
+}
+
+impl Prefs {
+    pub fn new(path: &Option<String>) -> Option<Self> {
+        Some(Self {
+            path: path.as_ref()?.into(),
+        })
+    }
+
+    pub fn get(&self, channel: &str) -> Option<String> {
+        let prefs = match fs::read_to_string(&self.path) {
+            Ok(prefs) => prefs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                error!("Failed to read preferences: {e}");
+                return None;
+            }
+        };
+
+        prefs
+            .lines()
+            .filter_map(|l| l.split_once('='))
+            .find(|(c, _)| *c == channel)
+            .map(|(_, q)| q.to_owned())
+            .filter(|q| !q.is_empty())
+    }
+
+    pub fn set(&self, channel: &str, quality: &str) {
+        if self.get(channel).is_some_and(|q| q == quality) {
+            return;
+        }
+
+        debug!("Saving quality {quality} for {channel}");
+        if let Err(e) = self.write(channel, quality) {
+            error!("Failed to write preferences: {e}");
+        }
+    }
+
+    //Quality to use when none was given on the command line
+    pub fn quality(&self, channel: &str) -> String {
+        if let Some(quality) = self.get(channel) {
+            info!("Using saved quality {quality} for {channel}");
+
+            //The saved quality may not exist anymore
+            return format!("{quality},best");
+        }
+
+        info!("No saved quality for {channel}, using best");
+        "best".to_owned()
+    }
+
+    fn write(&self, channel: &str, quality: &str) -> io::Result<()> {
+        let prefs = match fs::read_to_string(&self.path) {
+            Ok(prefs) => prefs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut out = String::new();
+        for line in prefs
+            .lines()
+            .filter(|l| l.split_once('=').is_none_or(|(c, _)| c != channel))
+        {
+            out.push_str(line);
+            out.push('\n');
+        }
+
+        out.push_str(channel);
+        out.push('=');
+        out.push_str(quality);
+        out.push('\n');
+
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        //Replaced atomically so concurrent instances never read a partial file
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, out)?;
+        fs::rename(&temp, &self.path)
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    io::{self, ErrorKind},
+    mem,
+    path::PathBuf,
+    str::Chars,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+
+use crate::{constants, http::Url};
+
+//Media sequences that were written, so an interrupted VOD download continues where it left off.
+//Saved as JSON keyed by sequence with the segment path, so a different playlist isn't trusted.
+pub struct ResumeState {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    segments: BTreeMap<usize, String>,
+    pending: BTreeMap<usize, String>, //downloading or queued for the recording
+    last_save: Instant,
+    dirty: bool,
+}
+
+impl Drop for ResumeState {
+    fn drop(&mut self) {
+        let inner = self.lock();
+        if inner.dirty
+            && let Err(e) = self.save(&inner)
+        {
+            warn!("Failed to save resume state: {e}");
+        }
+    }
+}
+
+impl ResumeState {
+    pub fn load(path: &str) -> Result<Self> {
+        let segments = match fs::read_to_string(path) {
+            Ok(json) => Self::parse(&json).unwrap_or_else(|| {
+                warn!("Ignoring malformed resume state in {path}");
+                BTreeMap::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::default(),
+            Err(e) => return Err(e).context("Failed to read resume state"),
+        };
+
+        if segments.is_empty() {
+            info!("Saving resume state to: {path}");
+        } else {
+            info!(
+                "Resuming from {path}, {} segments were already written",
+                segments.len()
+            );
+        }
+
+        Ok(Self {
+            path: PathBuf::from(path),
+            inner: Mutex::new(Inner {
+                segments,
+                pending: BTreeMap::default(),
+                last_save: Instant::now(),
+                dirty: false,
+            }),
+        })
+    }
+
+    //A sequence with a different path means it's not the same playlist, so everything is dropped
+    pub fn is_written(&self, sequence: usize, url: &Url) -> bool {
+        let mut inner = self.lock();
+        match inner.segments.get(&sequence) {
+            Some(written) if written == Self::key(url) => true,
+            Some(_) => {
+                warn!(
+                    "Playlist changed since {} was written, downloading every segment",
+                    self.path.display()
+                );
+
+                inner.segments.clear();
+                inner.dirty = true;
+                false
+            }
+            None => false,
+        }
+    }
+
+    //Registered before downloading, completed once the recording wrote it
+    pub fn begin(&self, sequence: usize, url: &Url) {
+        self.lock()
+            .pending
+            .insert(sequence, Self::key(url).to_owned());
+    }
+
+    //Older pending segments failed or were dropped before reaching the recording
+    pub fn complete(&self, sequence: usize) {
+        let mut inner = self.lock();
+        let newer = inner.pending.split_off(&(sequence + 1));
+        let Some(key) = mem::replace(&mut inner.pending, newer).remove(&sequence) else {
+            return;
+        };
+
+        inner.segments.insert(sequence, key);
+        inner.dirty = true;
+
+        if inner.last_save.elapsed() >= Duration::from_secs(constants::RESUME_STATE_SAVE_SECS) {
+            match self.save(&inner) {
+                Ok(()) => {
+                    debug!("Saved resume state with {} segments", inner.segments.len());
+                    inner.dirty = false;
+                }
+                Err(e) => warn!("Failed to save resume state: {e}"),
+            }
+
+            inner.last_save = Instant::now();
+        }
+    }
+
+    //Replaced atomically so an interruption never leaves a partial file
+    fn save(&self, inner: &Inner) -> io::Result<()> {
+        let mut json = String::from("{\"segments\":{");
+        for (i, (sequence, url)) in inner.segments.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            _ = write!(
+                json,
+                "{separator}\n\"{sequence}\":\"{}\"",
+                url.replace('\\', r"\\").replace('"', "\\\"")
+            );
+        }
+        json.push_str("\n}}\n");
+
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        fs::write(&temp, json)?;
+        fs::rename(&temp, &self.path)
+    }
+
+    //Only reads files written by save
+    fn parse(json: &str) -> Option<BTreeMap<usize, String>> {
+        let mut chars = json.split_once("\"segments\"")?.1.chars();
+        let mut segments = BTreeMap::new();
+        while let Some(sequence) = Self::next_string(&mut chars) {
+            let url = Self::next_string(&mut chars)?;
+            segments.insert(sequence.parse().ok()?, url);
+        }
+
+        Some(segments)
+    }
+
+    fn next_string(chars: &mut Chars) -> Option<String> {
+        chars.find(|c| *c == '"')?;
+
+        let mut string = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(string),
+                '\\' => string.push(chars.next()?),
+                c => string.push(c),
+            }
+        }
+    }
+
+    //Hosts and query parameters (signatures) change between sessions
+    fn key(url: &Url) -> &str {
+        let path = url.path().unwrap_or(url);
+        path.split('?').next().unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Resume state lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    //Unique per test so they can run in parallel
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("twitch-hls-client-{}-{name}.json", process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn written(state: &ResumeState) -> Vec<(usize, String)> {
+        state
+            .lock()
+            .segments
+            .iter()
+            .map(|(s, k)| (*s, k.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_escapes_paths() -> Result<()> {
+        let path = temp_path("round-trip");
+        let url = Url::from(r#"https://host/a"b\c.ts?sig=1"#);
+        {
+            let state = ResumeState::load(&path)?;
+            state.begin(7, &url);
+            state.complete(7);
+        }
+
+        let state = ResumeState::load(&path)?;
+        fs::remove_file(&path)?;
+
+        assert_eq!(written(&state), [(7, ResumeState::key(&url).to_owned())]);
+        assert!(state.is_written(7, &Url::from(r#"https://other/a"b\c.ts?sig=2"#)));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_file_is_ignored() -> Result<()> {
+        let path = temp_path("malformed");
+        for json in [
+            "garbage",
+            "{\"segments\":{\n\"1\":\"a.ts",
+            "{\"segments\":{\n\"x\":\"a.ts\"}}",
+        ] {
+            fs::write(&path, json)?;
+            let state = ResumeState::load(&path)?;
+            assert!(written(&state).is_empty(), "{json}");
+        }
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mismatch_clears_state() -> Result<()> {
+        let path = temp_path("mismatch");
+        fs::write(
+            &path,
+            "{\"segments\":{\n\"1\":\"a.ts\",\n\"2\":\"b.ts\"\n}}\n",
+        )?;
+        let state = ResumeState::load(&path)?;
+
+        assert!(state.is_written(1, &Url::from("https://host/a.ts")));
+        assert!(!state.is_written(2, &Url::from("https://host/c.ts")));
+        assert!(written(&state).is_empty());
+        assert!(!state.is_written(1, &Url::from("https://host/a.ts")));
+
+        drop(state);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn complete_drops_older_pending() -> Result<()> {
+        let path = temp_path("pending");
+        let state = ResumeState::load(&path)?;
+        for sequence in 1..=3 {
+            state.begin(sequence, &Url::from(format!("https://host/{sequence}.ts")));
+        }
+
+        state.complete(2);
+        state.complete(1);
+        assert_eq!(written(&state), [(2, "2.ts".to_owned())]);
+        assert_eq!(state.lock().pending.keys().collect::<Vec<_>>(), [&3]);
+
+        state.complete(3);
+        assert_eq!(
+            written(&state),
+            [(2, "2.ts".to_owned()), (3, "3.ts".to_owned())]
+        );
+
+        drop(state);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,22 +1,203 @@
 use std::{
     cmp::Ordering,
+    collections::VecDeque,
     fmt::{self, Display, Formatter},
     mem,
     str::FromStr,
-    sync::mpsc::{self, Sender},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
+        mpsc::{self, Receiver, Sender},
+    },
     thread::{self, Builder as ThreadBuilder, JoinHandle},
     time::{self, Instant},
 };
 
-use anyhow::{Context, Result, bail};
-use log::{debug, info};
+use anyhow::{Context, Result, bail, ensure};
+use getrandom::getrandom;
+use log::{debug, info, trace, warn};
+use regex::Regex;
 
-use super::playlist::{Playlist, QueueRange};
+use super::{
+    UrlPrinter,
+    playlist::{Playlist, QueueRange},
+    resume::ResumeState,
+};
 use crate::{
-    http::{Agent, Method, Request, StatusError, Url},
+    args::{Parse, Parser},
+    constants,
+    http::{
+        self, Agent, Method, Request, StatusError, TimeoutError, TooSmallError, Url, WriteError,
+    },
+    metrics,
     output::{Output, Writer},
 };
 
+//What to do when the playlist stops advancing
+#[derive(Default, Copy, Clone, Debug)]
+pub enum OnStall {
+    #[default]
+    Wait,
+    Warn,
+    Abort,
+}
+
+impl OnStall {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "wait" => Ok(Self::Wait),
+            "warn" => Ok(Self::Warn),
+            "abort" => Ok(Self::Abort),
+            _ => bail!("Invalid stall behavior, must be wait, warn or abort"),
+        }
+    }
+}
+
+//What the player gets in place of ad segments
+#[derive(Default, Copy, Clone, Debug)]
+pub enum Ads {
+    #[default]
+    Skip,
+    Freeze,
+}
+
+impl Ads {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "skip" => Ok(Self::Skip),
+            "freeze" => Ok(Self::Freeze),
+            _ => bail!("Invalid ad behavior, must be skip or freeze"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Args {
+    dedup_window: usize,
+    segment_timeout: Option<time::Duration>,
+    split_on_gap: bool,
+    gap_tolerance: usize,
+    on_stall: OnStall,
+    stall_reloads: u64,
+    #[cfg(not(unix))]
+    pause_file: Option<String>,
+    min_segment_size: usize,
+    prefetch_min: usize,
+    prefetch_max: usize,
+    ads: Ads,
+    reload_jitter: time::Duration,
+    segment_deny: Vec<Regex>,
+    segment_allow: Vec<Regex>,
+    segment_alt_hosts: bool,
+    pub(super) resume_state: Option<String>,
+    pub(super) print_urls: UrlPrinter,
+    pub(super) audio_fallback: bool, //set by --prefer-video-else-audio
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            dedup_window: 64,
+            segment_timeout: Option::default(),
+            split_on_gap: bool::default(),
+            gap_tolerance: 1,
+            on_stall: OnStall::default(),
+            stall_reloads: 10,
+            #[cfg(not(unix))]
+            pause_file: Option::default(),
+            min_segment_size: 1,
+            prefetch_min: 1,
+            prefetch_max: 2,
+            ads: Ads::default(),
+            reload_jitter: time::Duration::from_millis(100),
+            segment_deny: Vec::default(),
+            segment_allow: Vec::default(),
+            segment_alt_hosts: true,
+            resume_state: Option::default(),
+            print_urls: UrlPrinter::default(),
+            audio_fallback: bool::default(),
+        }
+    }
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse(&mut self.dedup_window, "--dedup-window")?;
+        ensure!(
+            self.dedup_window > 0,
+            "--dedup-window must be greater than 0"
+        );
+
+        parser.parse_fn(&mut self.segment_timeout, "--segment-timeout", |arg| {
+            Ok(Some(time::Duration::try_from_secs_f64(arg.parse()?)?))
+        })?;
+        ensure!(
+            self.segment_timeout.is_none_or(|t| !t.is_zero()),
+            "--segment-timeout must be greater than 0"
+        );
+
+        parser.parse_switch(&mut self.split_on_gap, "--split-on-gap")?;
+        parser.parse(&mut self.gap_tolerance, "--gap-tolerance")?;
+
+        parser.parse_fn(&mut self.on_stall, "--on-stall", OnStall::new)?;
+        parser.parse(&mut self.stall_reloads, "--stall-reloads")?;
+        ensure!(
+            self.stall_reloads > 0,
+            "--stall-reloads must be greater than 0"
+        );
+
+        #[cfg(not(unix))]
+        parser.parse_opt(&mut self.pause_file, "--pause-file")?;
+        parser.parse(&mut self.min_segment_size, "--min-segment-size")?;
+
+        parser.parse(&mut self.prefetch_min, "--prefetch-min")?;
+        parser.parse(&mut self.prefetch_max, "--prefetch-max")?;
+        ensure!(
+            self.prefetch_min > 0,
+            "--prefetch-min must be greater than 0"
+        );
+        ensure!(
+            self.prefetch_max >= self.prefetch_min,
+            "--prefetch-max cannot be lower than --prefetch-min"
+        );
+
+        parser.parse_fn(&mut self.ads, "--ads", Ads::new)?;
+        parser.parse_duration(&mut self.reload_jitter, "--reload-jitter")?;
+
+        parser.parse_repeated(&mut self.segment_deny, "--segment-deny", Self::parse_regex)?;
+        parser.parse_repeated(
+            &mut self.segment_allow,
+            "--segment-allow",
+            Self::parse_regex,
+        )?;
+        parser.parse_fn(
+            &mut self.segment_alt_hosts,
+            "--segment-alt-hosts",
+            |arg| match arg {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                _ => bail!("Invalid --segment-alt-hosts, must be on or off"),
+            },
+        )?;
+        parser.parse_opt(&mut self.resume_state, "--resume-state")?;
+
+        Ok(())
+    }
+}
+
+impl Args {
+    //Compiled once here instead of for every segment
+    fn parse_regex(arg: &str) -> Result<Regex> {
+        Ok(Regex::new(arg)?)
+    }
+
+    fn is_blocked(&self, url: &Url) -> bool {
+        self.segment_deny.iter().any(|p| p.is_match(url))
+            || (!self.segment_allow.is_empty()
+                && !self.segment_allow.iter().any(|p| p.is_match(url)))
+    }
+}
+
 #[derive(Debug)]
 pub struct ResetError;
 
@@ -28,57 +209,185 @@ impl Display for ResetError {
     }
 }
 
+#[derive(Debug)]
+pub struct VideoFailedError;
+
+impl std::error::Error for VideoFailedError {}
+
+impl Display for VideoFailedError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Unhandled video segment failures")
+    }
+}
+
 pub struct Handler {
     worker: Option<Worker>,
+    dedup: Dedup,
+    args: Args,
+    record_ads: bool,
     init: bool,
+    dispatched: u64,
+    unchanged: u64,
+    paused: bool,
+    pause: PauseToggle,
+    blocked: bool,
+    prefetch: Arc<PrefetchDepth>,
+    alt_hosts: Arc<AltHosts>,
+    resume: Option<Arc<ResumeState>>,
+    counters: Arc<Counters>,
+    audio_only: bool,
+}
+
+//Outputs are owned by the worker, wait for it so they're closed before returning
+impl Drop for Handler {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            drop(worker.join());
+        }
+    }
 }
 
 impl Handler {
-    pub fn new(writer: Writer, agent: &Agent) -> Result<Self> {
+    pub fn new(mut writer: Writer, agent: &Agent, args: &Args) -> Result<Self> {
+        if matches!(args.ads, Ads::Freeze) {
+            writer.enable_freeze();
+        }
+
+        let prefetch = Arc::new(PrefetchDepth::new(args.prefetch_min, args.prefetch_max));
+        let alt_hosts = Arc::new(AltHosts::default());
+        let resume = args
+            .resume_state
+            .as_deref()
+            .map(ResumeState::load)
+            .transpose()?
+            .map(Arc::new);
+        if let Some(resume) = &resume {
+            let resume = resume.clone();
+            ensure!(
+                writer.on_recorded(move |sequence| resume.complete(sequence)),
+                "--resume-state requires -r"
+            );
+        }
+        let counters = Arc::new(Counters::default());
         Ok(Self {
-            worker: Some(Worker::spawn(agent.binary(writer))?),
+            record_ads: writer.records_ads(),
+            worker: Some(Worker::spawn(
+                agent.binary(writer),
+                args.clone(),
+                prefetch.clone(),
+                alt_hosts.clone(),
+                resume.clone(),
+                counters.clone(),
+            )?),
+            dedup: Dedup::new(args.dedup_window),
+            args: args.clone(),
             init: true,
+            dispatched: u64::default(),
+            unchanged: u64::default(),
+            paused: bool::default(),
+            pause: PauseToggle::new(args)?,
+            blocked: bool::default(),
+            prefetch,
+            alt_hosts,
+            resume,
+            counters,
+            audio_only: bool::default(),
         })
     }
 
     pub fn process(&mut self, playlist: &mut Playlist, time: Instant) -> Result<()> {
+        self.update_paused()?;
+        self.check_failures()?;
+        if playlist.take_sequence_reset() {
+            self.dedup.clear();
+        }
+
+        if let Some(quality) = playlist.take_variant_change() {
+            self.audio_only = quality == constants::AUDIO_ONLY_QUALITY;
+            self.send(Job::Quality(quality))?;
+        }
+
         let last_duration = playlist
             .last_duration()
             .context("Failed to find last segment duration")?;
 
-        if last_duration.is_ad {
+        if last_duration.is_ad && !self.record_ads {
             info!("Filtering ad segment...");
-            last_duration.sleep(time.elapsed());
+            let ads = match playlist.segment_queue() {
+                QueueRange::Partial(_, segments) => segments.count() as u64,
+                QueueRange::Back(..) => 1,
+                QueueRange::Empty => 0,
+            };
+            metrics::add_ads_skipped(ads);
+
+            if matches!(self.args.ads, Ads::Freeze) {
+                for _ in 0..ads {
+                    self.send(Job::Freeze)?;
+                }
+            }
+
+            last_duration.sleep(time.elapsed(), self.args.reload_jitter);
 
             return Ok(());
         }
 
-        match playlist.segment_queue() {
-            QueueRange::Partial(ref mut segments) => {
-                for segment in segments {
-                    debug!("Processing segment:\n{segment:?}");
-                    match segment {
-                        Segment::Normal(_, url) | Segment::Prefetch(url) => self.dispatch(url)?,
+        let queue = playlist.segment_queue();
+        if matches!(queue, QueueRange::Empty) {
+            self.unchanged += 1;
+            if self.unchanged == self.args.stall_reloads {
+                self.stalled()?;
+            }
+        } else {
+            self.unchanged = 0;
+        }
+
+        match queue {
+            QueueRange::Partial(sequence, segments) => {
+                let depth = self.prefetch.get();
+                let mut prefetched = 0;
+                let mut deferred = None;
+                for (sequence, segment) in (sequence..).zip(segments) {
+                    //Prefetch segments are always last, the rest are queued again on the next reload
+                    if matches!(segment, Segment::Prefetch(_)) {
+                        if prefetched == depth {
+                            debug!("Prefetch depth of {depth} reached at segment {sequence}");
+                            deferred = Some(sequence);
+                            break;
+                        }
+
+                        prefetched += 1;
                     }
+
+                    self.dispatch_segment(sequence, segment, last_duration.is_ad)?;
+                }
+
+                if let Some(sequence) = deferred {
+                    playlist.defer_from(sequence);
                 }
 
-                last_duration.sleep(time.elapsed());
+                last_duration.sleep(time.elapsed(), self.args.reload_jitter);
                 self.init = false;
             }
-            QueueRange::Back(newest) => {
+            QueueRange::Back(sequence, newest) => {
                 if !self.init {
                     info!("Failed to find next segment, skipping to newest...");
                 }
 
                 let newest = newest.context("Failed to find newest segment")?;
-                debug!("Processing newest segment:\n{newest:?}");
+                debug!("Processing newest segment {sequence}:\n{newest:?}");
 
                 match newest {
                     Segment::Normal(duration, url) => {
-                        self.dispatch(url)?;
-                        duration.sleep(time.elapsed());
+                        self.dispatch(sequence, Some(duration.inner), duration.is_ad, url)?;
+                        duration.sleep(time.elapsed(), self.args.reload_jitter);
+                    }
+                    Segment::Prefetch(url) => {
+                        self.dispatch(sequence, None, last_duration.is_ad, url)?;
+                    }
+                    Segment::Malformed(duration) => {
+                        debug!("Newest segment {sequence} is malformed, skipping...");
+                        duration.sleep(time.elapsed(), self.args.reload_jitter);
                     }
-                    Segment::Prefetch(url) => self.dispatch(url)?,
                 }
             }
             QueueRange::Empty => {
@@ -86,28 +395,233 @@ impl Handler {
                     info!("Playlist unchanged, retrying...");
                 }
 
-                last_duration.sleep_half(time.elapsed());
+                last_duration.sleep_half(time.elapsed(), self.args.reload_jitter);
             }
         }
 
         Ok(())
     }
 
-    fn dispatch(&mut self, url: &mut Url) -> Result<()> {
-        if !self
+    //Writes every segment of the current playlist for --once, without waiting for reloads
+    pub fn process_once(mut self, playlist: &mut Playlist) -> Result<()> {
+        if let Some(quality) = playlist.take_variant_change() {
+            self.send(Job::Quality(quality))?;
+        }
+
+        let last_is_ad = playlist.last_duration().is_some_and(|d| d.is_ad);
+        match playlist.segment_queue() {
+            QueueRange::Partial(sequence, segments) => {
+                for (sequence, segment) in (sequence..).zip(segments) {
+                    if !self.record_ads && matches!(segment, Segment::Normal(d, _) if d.is_ad) {
+                        info!("Filtering ad segment...");
+                        continue;
+                    }
+
+                    self.dispatch_segment(sequence, segment, last_is_ad)?;
+                }
+            }
+            QueueRange::Back(sequence, Some(newest)) => {
+                self.dispatch_segment(sequence, newest, last_is_ad)?;
+            }
+            QueueRange::Back(_, None) | QueueRange::Empty => info!("Playlist has no segments"),
+        }
+
+        //Waits for every queued segment to be written
+        let (_, result) = self
+            .worker
+            .take()
+            .expect("Missing worker while finishing")
+            .join();
+
+        result
+    }
+
+    fn dispatch_segment(
+        &mut self,
+        sequence: usize,
+        segment: &mut Segment,
+        last_is_ad: bool,
+    ) -> Result<()> {
+        debug!("Processing segment {sequence}:\n{segment:?}");
+        match segment {
+            Segment::Normal(duration, url) => {
+                self.dispatch(sequence, Some(duration.inner), duration.is_ad, url)
+            }
+            Segment::Prefetch(url) => self.dispatch(sequence, None, last_is_ad, url),
+            Segment::Malformed(_) => {
+                debug!("Segment {sequence} is malformed, skipping...");
+                Ok(())
+            }
+        }
+    }
+
+    //Gives up on video after repeated segment failures with --prefer-video-else-audio
+    fn check_failures(&self) -> Result<()> {
+        if !self.args.audio_fallback
+            || self.audio_only
+            || self.counters.failures.load(AtomicOrdering::Relaxed)
+                < constants::AUDIO_FALLBACK_FAILURES
+        {
+            return Ok(());
+        }
+
+        self.counters.failures.store(0, AtomicOrdering::Relaxed);
+        Err(VideoFailedError.into())
+    }
+
+    //Only reported once per stall
+    fn stalled(&self) -> Result<()> {
+        let reloads = self.args.stall_reloads;
+        match self.args.on_stall {
+            OnStall::Wait => debug!("Playlist unchanged for {reloads} reloads, waiting..."),
+            OnStall::Warn => {
+                warn!("Playlist unchanged for {reloads} reloads, stream may be stalled");
+            }
+            OnStall::Abort => bail!("Playlist unchanged for {reloads} reloads, stream is stalled"),
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(
+        &mut self,
+        sequence: usize,
+        duration: Option<time::Duration>,
+        is_ad: bool,
+        url: &mut Url,
+    ) -> Result<()> {
+        if !self.dedup.insert(sequence) {
+            debug!("Segment {sequence} already downloaded, skipping...");
+            return Ok(());
+        }
+
+        if self.paused {
+            debug!("Recording paused, skipping segment {sequence}...");
+            return Ok(());
+        }
+
+        if let Some(resume) = &self.resume
+            && resume.is_written(sequence, url)
+        {
+            debug!("Segment {sequence} was written before resuming, skipping...");
+            return Ok(());
+        }
+
+        //One discontinuity for each run of filtered segments
+        if self.args.is_blocked(url) {
+            info!("Segment {sequence} filtered by --segment-deny/--segment-allow, skipping...");
+            if !mem::replace(&mut self.blocked, true) {
+                self.send(Job::Discontinuity)?;
+            }
+
+            return Ok(());
+        }
+        self.blocked = false;
+
+        if self.args.segment_alt_hosts {
+            self.alt_hosts.record(url);
+        }
+
+        if self.dispatched < constants::PRINT_URLS_SEGMENTS {
+            self.args
+                .print_urls
+                .print(&format!("Segment {sequence}"), url);
+        }
+
+        self.send(Job::Segment {
+            url: mem::take(url),
+            sequence,
+            duration,
+            is_ad,
+        })?;
+
+        self.dispatched += 1;
+        Ok(())
+    }
+
+    //Segments are still tracked while paused, but not downloaded
+    fn update_paused(&mut self) -> Result<()> {
+        let paused = self.pause.paused(self.paused);
+        if paused == self.paused {
+            return Ok(());
+        }
+
+        self.paused = paused;
+        metrics::set_paused(paused);
+        if paused {
+            info!("Recording paused");
+            return Ok(());
+        }
+
+        //Put the gap on a file boundary
+        info!("Recording resumed");
+        self.discontinuity()
+    }
+
+    pub const fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    //Number of segments downloaded by the worker
+    pub fn segments(&self) -> u64 {
+        self.counters.downloaded.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn set_header(&mut self, header: Vec<u8>) -> Result<()> {
+        self.send(Job::Header(header))
+    }
+
+    pub fn discontinuity(&mut self) -> Result<()> {
+        self.init = true;
+        self.send(Job::Discontinuity)
+    }
+
+    pub fn stream_ended(&mut self) -> Result<()> {
+        self.send(Job::StreamEnded)
+    }
+
+    fn send(&mut self, job: Job) -> Result<()> {
+        if let Err(job) = self
             .worker
             .as_mut()
-            .expect("Missing worker while sending URL")
-            .send(mem::take(url))
+            .expect("Missing worker while sending job")
+            .send(job)
         {
-            let mut request = self
+            let (mut request, result) = self
                 .worker
                 .take()
                 .expect("Missing worker while joining")
-                .join()?;
+                .join();
 
-            request.get_mut().wait_for_output()?;
-            self.worker = Some(Worker::spawn(request)?);
+            //Keep the worker alive so the session can be resumed if the error is recoverable
+            if let Err(error) = result {
+                Worker::apply(request.get_mut(), &job)?;
+                self.worker = Some(Worker::spawn(
+                    request,
+                    self.args.clone(),
+                    self.prefetch.clone(),
+                    self.alt_hosts.clone(),
+                    self.resume.clone(),
+                    self.counters.clone(),
+                )?);
+                self.init = true;
+
+                self.check_failures()?;
+                return Err(error);
+            }
+
+            //Don't lose the header or discontinuity, only the segment can be skipped
+            Worker::apply(request.get_mut(), &job)?;
+
+            request.get_mut().wait_for_output().context(WriteError)?;
+            self.worker = Some(Worker::spawn(
+                request,
+                self.args.clone(),
+                self.prefetch.clone(),
+                self.alt_hosts.clone(),
+                self.resume.clone(),
+                self.counters.clone(),
+            )?);
 
             self.init = true;
             return Err(ResetError.into());
@@ -117,46 +631,317 @@ impl Handler {
     }
 }
 
+//SIGUSR2 toggles pausing, checked on every playlist reload
+#[cfg(unix)]
+struct PauseToggle {
+    toggled: Arc<AtomicBool>,
+    id: signal_hook::SigId,
+}
+
+#[cfg(unix)]
+impl Drop for PauseToggle {
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.id);
+    }
+}
+
+#[cfg(unix)]
+impl PauseToggle {
+    fn new(_args: &Args) -> Result<Self> {
+        let toggled = Arc::new(AtomicBool::new(false));
+        let id = signal_hook::flag::register(signal_hook::consts::SIGUSR2, toggled.clone())
+            .context("Failed to handle SIGUSR2")?;
+
+        Ok(Self { toggled, id })
+    }
+
+    fn paused(&self, paused: bool) -> bool {
+        paused != self.toggled.swap(false, AtomicOrdering::Relaxed)
+    }
+}
+
+//No SIGUSR2 on other platforms, paused while --pause-file exists instead
+#[cfg(not(unix))]
+struct PauseToggle {
+    file: Option<String>,
+}
+
+#[cfg(not(unix))]
+impl PauseToggle {
+    fn new(args: &Args) -> Result<Self> {
+        Ok(Self {
+            file: args.pause_file.clone(),
+        })
+    }
+
+    fn paused(&self, paused: bool) -> bool {
+        self.file
+            .as_ref()
+            .map_or(paused, |path| std::path::Path::new(path).exists())
+    }
+}
+
+//Shared by every worker the handler spawns
+#[derive(Default)]
+struct Counters {
+    downloaded: AtomicU64,
+    failures: AtomicUsize, //consecutive failed segments
+}
+
+//Bounded set of recently downloaded media sequence numbers
+struct Dedup {
+    seen: VecDeque<usize>,
+    window: usize,
+}
+
+impl Dedup {
+    fn new(window: usize) -> Self {
+        Self {
+            seen: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    fn insert(&mut self, sequence: usize) -> bool {
+        if self.seen.contains(&sequence) {
+            return false;
+        }
+
+        if self.seen.len() == self.window {
+            self.seen.pop_front();
+        }
+
+        self.seen.push_back(sequence);
+        true
+    }
+
+    fn clear(&mut self) {
+        debug!("Clearing downloaded segment history");
+        self.seen.clear();
+    }
+}
+
+enum Job {
+    Segment {
+        url: Url,
+        sequence: usize,
+        duration: Option<time::Duration>,
+        is_ad: bool,
+    },
+    Header(Vec<u8>),
+    Quality(String),
+    Freeze,
+    Discontinuity,
+    StreamEnded,
+}
+
 struct Worker {
-    handle: JoinHandle<Result<Request<Writer>>>,
-    sender: Sender<Url>,
+    handle: JoinHandle<(Request<Writer>, Result<()>)>,
+    sender: Sender<Job>,
 }
 
 impl Worker {
-    fn spawn(mut request: Request<Writer>) -> Result<Self> {
-        let (sender, receiver) = mpsc::channel::<Url>();
+    fn spawn(
+        mut request: Request<Writer>,
+        args: Args,
+        prefetch: Arc<PrefetchDepth>,
+        alt_hosts: Arc<AltHosts>,
+        resume: Option<Arc<ResumeState>>,
+        counters: Arc<Counters>,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        request.set_min_body(args.min_segment_size);
         let handle = ThreadBuilder::new()
             .name("hls worker".to_owned())
-            .spawn(move || -> Result<Request<Writer>> {
-                loop {
-                    let Ok(url) = receiver.recv() else {
-                        bail!("Worker died unexpectantly");
-                    };
-
-                    match request.call(Method::Get, &url) {
-                        Ok(()) => (),
-                        Err(e) if StatusError::is_not_found(&e) => {
-                            info!("Segment not found, skipping ahead...");
-                            receiver.try_iter().for_each(drop);
-                        }
-                        Err(e) => return Err(e),
-                    }
-
-                    if request.get_ref().should_wait() {
-                        return Ok(request);
-                    }
-                }
+            .spawn(move || {
+                let result = Self::run(
+                    &mut request,
+                    &receiver,
+                    &args,
+                    &prefetch,
+                    &alt_hosts,
+                    resume.as_deref(),
+                    &counters,
+                );
+                (request, result)
             })
             .context("Failed to spawn worker")?;
 
         Ok(Self { handle, sender })
     }
 
-    fn send(&self, url: Url) -> bool {
-        self.sender.send(url).is_ok()
+    //Returns Ok when the worker should pause for outputs
+    fn run(
+        request: &mut Request<Writer>,
+        receiver: &Receiver<Job>,
+        args: &Args,
+        prefetch: &PrefetchDepth,
+        alt_hosts: &AltHosts,
+        resume: Option<&ResumeState>,
+        counters: &Counters,
+    ) -> Result<()> {
+        let mut last_sequence = None;
+        loop {
+            //Handler is done with the worker
+            let Ok(job) = receiver.recv() else {
+                return Ok(());
+            };
+
+            let Job::Segment {
+                url,
+                sequence,
+                duration,
+                is_ad,
+            } = job
+            else {
+                if matches!(job, Job::Discontinuity) {
+                    last_sequence = None;
+                }
+
+                Self::apply(request.get_mut(), &job)?;
+                continue;
+            };
+
+            if args.split_on_gap
+                && let Some(gap) = last_sequence.and_then(|l: usize| sequence.checked_sub(l + 1))
+                && gap > args.gap_tolerance
+            {
+                info!(
+                    "Missing {gap} segments before segment {sequence}, signaling discontinuity..."
+                );
+                request.get_mut().discontinuity().context(WriteError)?;
+            }
+            last_sequence = Some(sequence);
+
+            if let Some(resume) = resume {
+                resume.begin(sequence, &url);
+            }
+
+            request.get_mut().begin_segment(sequence, duration, is_ad);
+            let mut result = Self::fetch(request, &url, args);
+            let mut served = None;
+            if args.segment_alt_hosts && result.as_ref().is_err_and(http::is_transient_error) {
+                served = Self::fetch_alt_hosts(request, &url, sequence, args, alt_hosts);
+                if served.is_some() {
+                    result = Ok(());
+                }
+            }
+
+            match &result {
+                Ok(()) if !request.retried() && served.is_none() => prefetch.success(),
+                _ => prefetch.failure(),
+            }
+
+            if args.segment_alt_hosts && result.is_ok() {
+                let host = served.as_ref().unwrap_or(&url).host().unwrap_or_default();
+                debug!("Segment {sequence} served by {host}");
+            }
+
+            //Segments that are gone aren't failures, they're skipped at the live edge
+            match &result {
+                Ok(()) => {
+                    counters.failures.store(0, AtomicOrdering::Relaxed);
+                    counters.downloaded.fetch_add(1, AtomicOrdering::Relaxed);
+                }
+                Err(e) if StatusError::is_not_found(e) => (),
+                Err(_) => _ = counters.failures.fetch_add(1, AtomicOrdering::Relaxed),
+            }
+
+            match result {
+                Ok(()) => (),
+                Err(e) if StatusError::is_not_found(&e) => {
+                    info!("Segment not found, skipping ahead...");
+                    Self::skip_queued(request, receiver)?;
+                }
+                Err(e) if e.is::<TooSmallError>() => {
+                    warn!("Segment {sequence} is smaller than --min-segment-size, skipping...");
+                }
+                Err(e) if e.is::<TimeoutError>() => {
+                    info!("Segment {sequence} timed out, skipping ahead...");
+                    request.get_mut().discontinuity().context(WriteError)?;
+                    Self::skip_queued(request, receiver)?;
+                    last_sequence = None;
+                }
+                Err(e) => return Err(e),
+            }
+
+            if request.get_ref().should_wait() {
+                return Ok(());
+            }
+        }
+    }
+
+    //A failed segment may have been partially written, which is discarded before anything else
+    fn fetch(request: &mut Request<Writer>, url: &Url, args: &Args) -> Result<()> {
+        let result = match args.segment_timeout {
+            Some(timeout) => request.call_timeout(Method::Get, url, timeout),
+            None => request.call(Method::Get, url),
+        };
+
+        if result.is_err() {
+            request.get_mut().abort_segment().context(WriteError)?;
+        }
+
+        result
+    }
+
+    //Tries the same path once on each other known host, returns the URL that worked
+    fn fetch_alt_hosts(
+        request: &mut Request<Writer>,
+        url: &Url,
+        sequence: usize,
+        args: &Args,
+        alt_hosts: &AltHosts,
+    ) -> Option<Url> {
+        let retries = request.retries();
+        request.set_retries(0);
+
+        let mut served = None;
+        for alt in alt_hosts.alternates(url) {
+            debug!(
+                "Retrying segment {sequence} on {}",
+                alt.host().unwrap_or_default()
+            );
+
+            match Self::fetch(request, &alt, args) {
+                Ok(()) => {
+                    served = Some(alt);
+                    break;
+                }
+                Err(e) => debug!("Alternate host failed: {e}"),
+            }
+        }
+
+        request.set_retries(retries);
+        served
+    }
+
+    //Queued segments are already stale, next reload will move towards the newest one
+    fn skip_queued(request: &mut Request<Writer>, receiver: &Receiver<Job>) -> Result<()> {
+        for job in receiver.try_iter() {
+            Self::apply(request.get_mut(), &job)?;
+        }
+
+        Ok(())
+    }
+
+    //Segments are ignored, only used for jobs that don't download anything
+    fn apply(writer: &mut Writer, job: &Job) -> Result<()> {
+        match job {
+            Job::Header(header) => writer.set_header(header).context(WriteError),
+            Job::Quality(quality) => writer.set_quality(quality).context(WriteError),
+            Job::Freeze => writer.freeze().context(WriteError),
+            Job::Discontinuity => writer.discontinuity().context(WriteError),
+            Job::StreamEnded => writer.stream_ended().context(WriteError),
+            Job::Segment { .. } => Ok(()),
+        }
     }
 
-    fn join(self) -> Result<Request<Writer>> {
+    fn send(&self, job: Job) -> Result<(), Job> {
+        self.sender.send(job).map_err(|e| e.0)
+    }
+
+    fn join(self) -> (Request<Writer>, Result<()>) {
         drop(self.sender);
         self.handle.join().expect("Worker panicked")
     }
@@ -166,6 +951,7 @@ impl Worker {
 pub enum Segment {
     Normal(Duration, Url),
     Prefetch(Url),
+    Malformed(Duration), //never downloaded, keeps the media sequence of later segments
 }
 
 #[derive(Default, Copy, Clone, Debug)]
@@ -210,25 +996,433 @@ impl Duration {
         inner: time::Duration::from_secs(3),
     };
 
-    pub fn sleep(&self, elapsed: time::Duration) {
+    //Assumed for a malformed duration when there's nothing to estimate it from
+    pub(super) const FALLBACK: Self = Self {
+        is_ad: false,
+        inner: time::Duration::from_secs(2),
+    };
+
+    pub const fn as_secs_f64(&self) -> f64 {
+        self.inner.as_secs_f64()
+    }
+
+    pub const fn inner(&self) -> time::Duration {
+        self.inner
+    }
+
+    // #EXT-X-TARGETDURATION:6
+    pub fn from_target(arg: &str) -> Option<Self> {
+        Some(Self {
+            is_ad: false,
+            inner: time::Duration::try_from_secs_f32(arg.trim().parse().ok()?).ok()?,
+        })
+    }
+
+    pub fn sleep(&self, elapsed: time::Duration, jitter: time::Duration) {
         if *self >= Self::MAX {
-            self.sleep_half(elapsed);
+            self.sleep_half(elapsed, jitter);
             return;
         }
 
-        Self::sleep_thread(self.inner, elapsed);
+        Self::sleep_thread(self.inner, elapsed, jitter);
     }
 
-    pub fn sleep_half(&self, elapsed: time::Duration) {
+    pub fn sleep_half(&self, elapsed: time::Duration, jitter: time::Duration) {
         if let Some(half) = self.inner.checked_div(2) {
-            Self::sleep_thread(half, elapsed);
+            Self::sleep_thread(half, elapsed, jitter);
         }
     }
 
-    fn sleep_thread(duration: time::Duration, elapsed: time::Duration) {
+    //Random offset of up to `jitter` so many clients don't reload in sync
+    fn sleep_thread(duration: time::Duration, elapsed: time::Duration, jitter: time::Duration) {
         if let Some(sleep_time) = duration.checked_sub(elapsed) {
+            let mut buf = [0u8; 4];
+            let offset = if jitter.is_zero() || getrandom(&mut buf).is_err() {
+                time::Duration::ZERO
+            } else {
+                jitter.mul_f64(f64::from(u32::from_ne_bytes(buf)) / f64::from(u32::MAX))
+            };
+
+            let sleep_time = sleep_time + offset;
             debug!("Sleeping thread for {sleep_time:?}");
+            trace!("Reload jitter: {offset:?}");
             thread::sleep(sleep_time);
         }
     }
 }
+
+//Number of prefetch segments downloaded per reload, shared with the worker.
+//Halved on every segment error and raised by one after a run of successes.
+struct PrefetchDepth {
+    depth: AtomicUsize,
+    successes: AtomicUsize,
+    min: usize,
+    max: usize,
+}
+
+impl PrefetchDepth {
+    const fn new(min: usize, max: usize) -> Self {
+        Self {
+            depth: AtomicUsize::new(max),
+            successes: AtomicUsize::new(0),
+            min,
+            max,
+        }
+    }
+
+    fn get(&self) -> usize {
+        self.depth.load(AtomicOrdering::Relaxed)
+    }
+
+    fn success(&self) {
+        let depth = self.get();
+        if depth == self.max {
+            return;
+        }
+
+        let successes = self.successes.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+        if successes >= constants::PREFETCH_RESTORE_SUCCESSES {
+            self.successes.store(0, AtomicOrdering::Relaxed);
+            self.depth.store(depth + 1, AtomicOrdering::Relaxed);
+            debug!("Prefetch depth raised to {}", depth + 1);
+        }
+    }
+
+    fn failure(&self) {
+        self.successes.store(0, AtomicOrdering::Relaxed);
+
+        let depth = self.get();
+        let lowered = (depth / 2).max(self.min);
+        if lowered < depth {
+            self.depth.store(lowered, AtomicOrdering::Relaxed);
+            debug!("Prefetch depth lowered to {lowered} after segment error");
+        }
+    }
+}
+
+//Hosts that have served segments, so a failing host can be worked around
+#[derive(Default)]
+struct AltHosts(Mutex<VecDeque<String>>);
+
+impl AltHosts {
+    fn record(&self, url: &Url) {
+        let Ok(authority) = url.authority() else {
+            return;
+        };
+
+        let mut hosts = self.0.lock().expect("Alternate hosts lock poisoned");
+        if hosts.iter().any(|h| h == authority) {
+            return;
+        }
+
+        if hosts.len() == constants::MAX_ALT_HOSTS {
+            hosts.pop_front();
+        }
+        hosts.push_back(authority.to_owned());
+        debug!("Known segment hosts: {hosts:?}");
+        drop(hosts);
+    }
+
+    //Most recently seen first
+    fn alternates(&self, url: &Url) -> Vec<Url> {
+        let current = url.authority().ok();
+        self.0
+            .lock()
+            .expect("Alternate hosts lock poisoned")
+            .iter()
+            .rev()
+            .filter(|h| current != Some(h.as_str()))
+            .filter_map(|h| url.with_authority(h).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Write as _,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{
+        hls::Args as HlsArgs,
+        http::{
+            Connection,
+            mock::{self, Response, Server, set},
+        },
+        output::{Args as OutputArgs, Event, Memory},
+    };
+
+    //Playlist at /playlist.m3u8, every other path is a segment containing its path,
+    //paths starting with /stall never finish in time
+    fn server() -> (Server, Arc<Mutex<String>>) {
+        let playlist = Arc::new(Mutex::new(String::new()));
+        let shared = playlist.clone();
+        let server = Server::new(move |path| {
+            if path == "/playlist.m3u8" {
+                return Response::ok(shared.lock().expect("Playlist lock poisoned").as_str());
+            }
+
+            if path.starts_with("/stall") {
+                return Response::ok(path).stall(time::Duration::from_millis(500));
+            }
+
+            Response::ok(path)
+        });
+
+        (server, playlist)
+    }
+
+    fn handler(args: &Args) -> Result<(Handler, Memory)> {
+        let memory = Memory::new();
+        let writer = Writer::new(
+            &OutputArgs::default(),
+            "test",
+            vec![Box::new(memory.clone())],
+        )?;
+
+        Ok((Handler::new(writer, &mock::agent(), args)?, memory))
+    }
+
+    fn playlist(server: &Server) -> Result<Playlist> {
+        let conn = Connection::new(server.url("/playlist.m3u8"), mock::agent().text());
+        Playlist::new(conn, None, &HlsArgs::default())
+    }
+
+    #[test]
+    fn dedup_evicts_oldest() {
+        let mut dedup = Dedup::new(2);
+        assert!(dedup.insert(1));
+        assert!(dedup.insert(2));
+        assert!(!dedup.insert(1));
+
+        assert!(dedup.insert(3));
+        assert!(!dedup.insert(3));
+        assert!(!dedup.insert(2));
+        assert!(dedup.insert(1), "oldest sequence should have been evicted");
+    }
+
+    #[test]
+    fn dedup_clear() {
+        let mut dedup = Dedup::new(4);
+        dedup.insert(7);
+        dedup.clear();
+
+        assert!(dedup.insert(7));
+    }
+
+    #[test]
+    fn segment_filters() -> Result<()> {
+        let args = Args {
+            segment_deny: vec![Args::parse_regex(r"(ad|preroll)/")?],
+            segment_allow: vec![Args::parse_regex(r"\d{3}\.ts$")?],
+            ..Default::default()
+        };
+
+        assert!(!args.is_blocked(&Url::from("https://host/live/123.ts")));
+        assert!(args.is_blocked(&Url::from("https://host/ad/123.ts")));
+        assert!(args.is_blocked(&Url::from("https://host/live/12.ts")));
+        assert!(args.is_blocked(&Url::from("https://host/live/123.ts?x")));
+        assert!(Args::parse_regex("(unclosed").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_reset_clears_dedup() -> Result<()> {
+        let (server, text) = server();
+        set(&text, &mock::media_playlist(0, &["s0", "s1"]));
+
+        let (mut handler, memory) = handler(&Args::default())?;
+        let mut playlist = playlist(&server)?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        set(&text, &mock::media_playlist(1, &["s1", "s2"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        //Sequence 2 was already downloaded, but it's a different segment after the reset
+        set(&text, &mock::media_playlist(0, &["r0", "r1", "r2"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+        assert!(
+            !playlist.take_sequence_reset(),
+            "reset is only reported once"
+        );
+        drop(handler);
+
+        assert_eq!(
+            memory.segments(),
+            [b"/s1".to_vec(), b"/s2".into(), b"/r2".into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_wrap_without_reset_skips_seen() -> Result<()> {
+        let (server, text) = server();
+        set(&text, &mock::media_playlist(5, &["s5", "s6"]));
+
+        let (mut handler, memory) = handler(&Args::default())?;
+        let mut playlist = playlist(&server)?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        //Same sequence repeated by the server isn't downloaded again
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+        drop(handler);
+
+        assert_eq!(memory.segments(), [b"/s6".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn prefetch_over_depth_is_deferred() -> Result<()> {
+        let (server, text) = server();
+        let prefetch = |sequence, segments: &[&str], prefetch: &[&str]| {
+            let mut playlist = mock::media_playlist(sequence, segments);
+            for segment in prefetch {
+                _ = writeln!(playlist, "#EXT-X-TWITCH-PREFETCH:{segment}");
+            }
+
+            playlist
+        };
+        set(&text, &mock::media_playlist(0, &["s0"]));
+
+        let (mut handler, memory) = handler(&Args {
+            prefetch_min: 1,
+            prefetch_max: 1,
+            ..Args::default()
+        })?;
+        let mut playlist = playlist(&server)?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        set(&text, &prefetch(0, &["s0"], &["s1", "s2"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        //s2 was over the depth, it's queued again even though the reload adds s3 after it
+        set(&text, &prefetch(0, &["s0", "s1", "s2"], &["s3"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        set(&text, &mock::media_playlist(1, &["s1", "s2", "s3", "s4"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+        drop(handler);
+
+        assert_eq!(
+            memory.segments(),
+            [
+                b"/s0".to_vec(),
+                b"/s1".into(),
+                b"/s2".into(),
+                b"/s3".into(),
+                b"/s4".into()
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pause_toggle_skips_segments() -> Result<()> {
+        let (server, text) = server();
+        set(&text, &mock::media_playlist(0, &["s0"]));
+
+        let (mut handler, memory) = handler(&Args::default())?;
+        let mut playlist = playlist(&server)?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        //Set like SIGUSR2 would, raising it would pause every other test's handler too
+        handler.pause.toggled.store(true, AtomicOrdering::Relaxed);
+        set(&text, &mock::media_playlist(0, &["s0", "s1"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+        assert!(handler.is_paused());
+
+        handler.pause.toggled.store(true, AtomicOrdering::Relaxed);
+        set(&text, &mock::media_playlist(0, &["s0", "s1", "s2"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+        assert!(!handler.is_paused());
+        drop(handler);
+
+        assert_eq!(memory.segments(), [b"/s0".to_vec(), b"/s2".into()]);
+        assert!(
+            memory
+                .events()
+                .iter()
+                .any(|e| matches!(e, Event::Discontinuity))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn timed_out_segment_is_discarded() -> Result<()> {
+        let (server, text) = server();
+        set(&text, &mock::media_playlist(0, &["s0", "stall1"]));
+
+        let (mut handler, memory) = handler(&Args {
+            segment_timeout: Some(time::Duration::from_millis(100)),
+            ..Args::default()
+        })?;
+        let mut playlist = playlist(&server)?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        //Queued segments are skipped after a timeout, so wait for it first
+        thread::sleep(time::Duration::from_millis(300));
+        set(&text, &mock::media_playlist(1, &["stall1", "s2"]));
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+        drop(handler);
+
+        let events = memory.events();
+        let abort = events
+            .iter()
+            .position(|e| matches!(e, Event::Abort))
+            .context("Missing abort")?;
+        assert!(
+            matches!(events[..abort].last(), Some(Event::Write(data)) if data.starts_with(b"/st")),
+            "partial segment should have been written before the abort"
+        );
+        assert!(matches!(events.get(abort + 1), Some(Event::Discontinuity)));
+        assert_eq!(memory.segments(), [b"/s2".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_failures_fall_back_to_audio() -> Result<()> {
+        let (server, text) = server();
+        set(&text, &mock::media_playlist(0, &["s0"]));
+
+        //Every segment is smaller than the minimum
+        let (mut handler, _) = handler(&Args {
+            min_segment_size: 100,
+            audio_fallback: true,
+            ..Args::default()
+        })?;
+        let mut playlist = playlist(&server)?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        set(
+            &text,
+            &mock::media_playlist(0, &["s0", "s1", "s2", "s3", "s4"]),
+        );
+        playlist.reload()?;
+        handler.process(&mut playlist, Instant::now())?;
+
+        thread::sleep(time::Duration::from_millis(200));
+        assert_eq!(handler.segments(), 0, "failed segments aren't downloaded");
+
+        playlist.reload()?;
+        let error = handler
+            .process(&mut playlist, Instant::now())
+            .expect_err("Video failures weren't reported");
+        assert!(error.is::<VideoFailedError>());
+
+        //Counted again from zero
+        handler.process(&mut playlist, Instant::now())
+    }
+}
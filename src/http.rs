@@ -1,6 +1,9 @@
 mod decoder;
+#[cfg(test)]
+pub mod mock;
 mod request;
 mod socks5;
+mod tls;
 mod url;
 
 pub use request::{Request, TextRequest};
@@ -9,15 +12,16 @@ pub use url::{Scheme, Url};
 use std::{
     borrow::Cow,
     fmt::{self, Display, Formatter},
+    fs::File,
     io::Write,
-    net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex},
     time::Duration,
 };
 
-use anyhow::Result;
-use log::{debug, error};
-use rustls::{ClientConfig, RootCertStore};
+use anyhow::{Context, Result, bail, ensure};
+use log::trace;
+use rustls::ClientConfig;
 
 use crate::{
     args::{Parse, Parser},
@@ -43,15 +47,83 @@ impl StatusError {
     }
 }
 
+#[derive(Debug)]
+pub struct TimeoutError(Url);
+
+impl std::error::Error for TimeoutError {}
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Request timed out on {}", self.0)
+    }
+}
+
+//Response body was smaller than the minimum size, ie. an empty segment from a proxy hiccup
+#[derive(Debug)]
+pub struct TooSmallError(usize, Url);
+
+impl std::error::Error for TooSmallError {}
+
+impl Display for TooSmallError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Response of {} bytes is too small on {}", self.0, self.1)
+    }
+}
+
+//Context for errors caused by the response writer rather than the connection
+#[derive(Debug)]
+pub struct WriteError;
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Output error")
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+enum IpVersion {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "4" => Ok(Self::V4),
+            "6" => Ok(Self::V6),
+            "any" => Ok(Self::Any),
+            _ => bail!("Invalid IP version"),
+        }
+    }
+
+    const fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::V4 => addr.is_ipv4(),
+            Self::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Args {
     force_https: bool,
     force_ipv4: bool,
+    ip_version: IpVersion,
+    bind: Option<IpAddr>,
     retries: u64,
     timeout: Duration,
+    max_playlist_size: usize,
     user_agent: Cow<'static, str>,
     socks5: Option<Vec<SocketAddr>>,
     socks5_restrict: Option<Vec<String>>,
+    cafile: Option<String>,
+    insecure: bool,
+    max_redirects: u64,
+    allow_downgrade: bool,
+    max_connections: usize,
 }
 
 impl Default for Args {
@@ -59,11 +131,19 @@ impl Default for Args {
         Self {
             retries: 3,
             timeout: Duration::from_secs(10),
+            max_playlist_size: 256 * 1024,
             user_agent: constants::USER_AGENT.into(),
             force_https: bool::default(),
             force_ipv4: bool::default(),
+            ip_version: IpVersion::default(),
+            bind: Option::default(),
             socks5: Option::default(),
             socks5_restrict: Option::default(),
+            max_redirects: 5,
+            max_connections: 6,
+            cafile: Option::default(),
+            insecure: bool::default(),
+            allow_downgrade: bool::default(),
         }
     }
 }
@@ -72,13 +152,55 @@ impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
         parser.parse_switch(&mut self.force_https, "--force-https")?;
         parser.parse_switch(&mut self.force_ipv4, "--force-ipv4")?;
+        parser.parse_fn(&mut self.ip_version, "--ip-version", IpVersion::new)?;
+        parser.parse_opt(&mut self.bind, "--bind")?;
         parser.parse(&mut self.retries, "--http-retries")?;
         parser.parse_duration(&mut self.timeout, "--http-timeout")?;
+        parser.parse(&mut self.max_playlist_size, "--max-playlist-size")?;
         parser.parse_cow_string(&mut self.user_agent, "--user-agent")?;
         parser.parse_fn(&mut self.socks5, "--socks5", |arg| {
             Ok(Some(arg.to_socket_addrs()?.collect()))
         })?;
         parser.parse_comma_list(&mut self.socks5_restrict, "--socks5-restrict")?;
+        parser.parse_opt(&mut self.cafile, "--cafile")?;
+        parser.parse_switch(&mut self.insecure, "--insecure")?;
+        parser.parse(&mut self.max_redirects, "--max-redirects")?;
+        parser.parse_switch(&mut self.allow_downgrade, "--allow-downgrade")?;
+        parser.parse(&mut self.max_connections, "--max-connections")?;
+        ensure!(
+            self.max_connections > 0,
+            "--max-connections must be greater than 0"
+        );
+
+        if let Some(cafile) = &self.cafile {
+            ensure!(
+                !self.insecure,
+                "--cafile and --insecure cannot be used together"
+            );
+            File::open(cafile).with_context(|| format!("Failed to open --cafile {cafile}"))?;
+        }
+
+        if self.force_ipv4 {
+            ensure!(
+                self.ip_version != IpVersion::V6,
+                "--force-ipv4 and --ip-version 6 cannot be used together"
+            );
+            self.ip_version = IpVersion::V4;
+        }
+
+        if let Some(bind) = &self.bind {
+            let bind_version = if bind.is_ipv4() {
+                IpVersion::V4
+            } else {
+                IpVersion::V6
+            };
+
+            ensure!(
+                self.ip_version == IpVersion::Any || self.ip_version == bind_version,
+                "--bind address doesn't match --ip-version"
+            );
+            self.ip_version = bind_version;
+        }
 
         Ok(())
     }
@@ -105,32 +227,16 @@ impl Display for Method {
 pub struct Agent {
     args: Arc<Args>,
     tls_config: Arc<ClientConfig>,
+    connections: Arc<Semaphore>,
 }
 
 impl Agent {
-    pub fn new(args: Args) -> Self {
-        let mut roots = RootCertStore::empty();
-        let res = rustls_native_certs::load_native_certs();
-
-        for error in res.errors {
-            error!("Failed to load certificates: {error}");
-        }
-
-        for cert in res.certs {
-            //Ignore parsing errors, OS can have broken certs
-            if let Err(e) = roots.add(cert) {
-                debug!("Invalid certificate: {e}");
-            }
-        }
-
-        Self {
+    pub fn new(args: Args) -> Result<Self> {
+        Ok(Self {
+            tls_config: Arc::new(tls::config(&args)?),
+            connections: Arc::new(Semaphore::new(args.max_connections)),
             args: Arc::new(args),
-            tls_config: Arc::new(
-                ClientConfig::builder()
-                    .with_root_certificates(Arc::new(roots))
-                    .with_no_client_auth(),
-            ),
-        }
+        })
     }
 
     pub fn text(&self) -> TextRequest {
@@ -151,6 +257,60 @@ impl Agent {
     }
 }
 
+//Limits requests in flight at once across every request made with an agent
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+    limit: usize,
+}
+
+impl Semaphore {
+    const fn new(limit: usize) -> Self {
+        Self {
+            available: Mutex::new(limit),
+            released: Condvar::new(),
+            limit,
+        }
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        let mut available = self.available.lock().expect("Semaphore lock poisoned");
+        if *available == 0 {
+            trace!(
+                "Waiting for a free connection, {} requests in flight",
+                self.limit
+            );
+        }
+
+        while *available == 0 {
+            available = self
+                .released
+                .wait(available)
+                .expect("Semaphore lock poisoned");
+        }
+        *available -= 1;
+        drop(available);
+
+        Permit(self)
+    }
+}
+
+struct Permit<'a>(&'a Semaphore);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().expect("Semaphore lock poisoned") += 1;
+        self.0.released.notify_one();
+    }
+}
+
+//Connection failures that may resolve themselves, ie. network outages or server errors
+pub fn is_transient_error(error: &anyhow::Error) -> bool {
+    !error.is::<WriteError>()
+        && (error.is::<StatusError>() && !StatusError::is_not_found(error)
+            || error.is::<std::io::Error>())
+}
+
 //Helper for passing around a url with a text request
 pub struct Connection {
     pub url: Url,
@@ -1,14 +1,23 @@
-use std::io::{self, Read};
+use std::io::{self, Read, Take};
 
 use anyhow::{Result, bail};
-use flate2::read::GzDecoder;
+use flate2::read::{GzDecoder, ZlibDecoder};
 use log::debug;
 
 enum Encoding<R: Read> {
     Unencoded(R, u64),
     Chunked(ChunkDecoder<R>),
     ChunkedGzip(GzDecoder<ChunkDecoder<R>>),
-    Gzip(GzDecoder<R>),
+    ChunkedDeflate(ZlibDecoder<ChunkDecoder<R>>),
+    Gzip(GzDecoder<Take<R>>),
+    Deflate(ZlibDecoder<Take<R>>),
+}
+
+#[derive(Copy, Clone)]
+enum Compression {
+    None,
+    Gzip,
+    Deflate,
 }
 
 pub struct Decoder<R: Read> {
@@ -35,7 +44,16 @@ impl<R: Read> Read for Decoder<R> {
 
                 Ok(consumed)
             }
+            Encoding::ChunkedDeflate(reader) => {
+                let consumed = reader.read(buf)?;
+                if consumed == 0 {
+                    io::copy(&mut reader.get_mut(), &mut io::sink())?;
+                }
+
+                Ok(consumed)
+            }
             Encoding::Gzip(reader) => reader.read(buf),
+            Encoding::Deflate(reader) => reader.read(buf),
         }
     }
 }
@@ -44,13 +62,17 @@ impl<R: Read> Decoder<R> {
     pub fn new(reader: R, headers: &str) -> Result<Self> {
         let mut content_length = None;
         let mut is_chunked = false;
-        let mut is_gzipped = false;
+        let mut compression = Compression::None;
 
         for line in headers.lines() {
             let mut split = line.split_whitespace();
             match split.next() {
                 Some("content-encoding:") => {
-                    is_gzipped = split.next().is_some_and(|h| h == "gzip");
+                    compression = match split.next() {
+                        Some("gzip" | "x-gzip") => Compression::Gzip,
+                        Some("deflate") => Compression::Deflate,
+                        _ => Compression::None,
+                    };
                 }
                 Some("transfer-encoding:") => {
                     is_chunked = split.next().is_some_and(|h| h == "chunked");
@@ -62,43 +84,45 @@ impl<R: Read> Decoder<R> {
             }
         }
 
-        match (is_chunked, is_gzipped) {
-            (true, true) => {
+        let kind = match (is_chunked, compression) {
+            (true, Compression::Gzip) => {
                 debug!("Body is chunked and gzipped");
-
-                Ok(Self {
-                    kind: Encoding::ChunkedGzip(GzDecoder::new(ChunkDecoder::new(reader))),
-                    consumed: u64::default(),
-                })
+                Encoding::ChunkedGzip(GzDecoder::new(ChunkDecoder::new(reader)))
+            }
+            (true, Compression::Deflate) => {
+                debug!("Body is chunked and deflated");
+                Encoding::ChunkedDeflate(ZlibDecoder::new(ChunkDecoder::new(reader)))
             }
-            (true, false) => {
+            (true, Compression::None) => {
                 debug!("Body is chunked");
-
-                Ok(Self {
-                    kind: Encoding::Chunked(ChunkDecoder::new(reader)),
-                    consumed: u64::default(),
-                })
+                Encoding::Chunked(ChunkDecoder::new(reader))
             }
-            (false, true) => {
+            //Limit to content length so the decoder can't read into the next response
+            (false, Compression::Gzip) => {
                 debug!("Body is gzipped");
-
-                Ok(Self {
-                    kind: Encoding::Gzip(GzDecoder::new(reader)),
-                    consumed: u64::default(),
-                })
+                Encoding::Gzip(GzDecoder::new(
+                    reader.take(content_length.unwrap_or(u64::MAX)),
+                ))
             }
-            (false, false) => match content_length {
+            (false, Compression::Deflate) => {
+                debug!("Body is deflated");
+                Encoding::Deflate(ZlibDecoder::new(
+                    reader.take(content_length.unwrap_or(u64::MAX)),
+                ))
+            }
+            (false, Compression::None) => match content_length {
                 Some(length) => {
                     debug!("Content length: {length}");
-
-                    Ok(Self {
-                        kind: Encoding::Unencoded(reader, length),
-                        consumed: u64::default(),
-                    })
+                    Encoding::Unencoded(reader, length)
                 }
                 None => bail!("Failed to resolve encoding of HTTP response"),
             },
-        }
+        };
+
+        Ok(Self {
+            kind,
+            consumed: u64::default(),
+        })
     }
 }
 
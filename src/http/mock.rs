@@ -0,0 +1,197 @@
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use super::{Agent, Args, Url};
+
+type Handler = dyn Fn(&str) -> Response + Send + Sync;
+
+pub struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    chunked: bool,
+    delay: Option<Duration>, //before the rest of the body, after the first half
+}
+
+impl Response {
+    pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: body.into(),
+            chunked: false,
+            delay: None,
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            ..Self::ok("")
+        }
+    }
+
+    pub fn redirect(status: u16, location: &str) -> Self {
+        Self::status(status).header("Location", location)
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_owned()));
+        self
+    }
+
+    pub const fn chunked(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+
+    pub const fn stall(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    fn write(&self, stream: &mut TcpStream, head: bool) -> std::io::Result<()> {
+        let mut response = format!("HTTP/1.1 {} Mock\r\n", self.status);
+        for (name, value) in &self.headers {
+            _ = write!(response, "{name}: {value}\r\n");
+        }
+
+        let body = if self.chunked {
+            response.push_str("Transfer-Encoding: chunked\r\n\r\n");
+
+            let mut body = Vec::new();
+            for chunk in self.body.chunks(7) {
+                _ = write!(body, "{:x}\r\n", chunk.len());
+                body.extend_from_slice(chunk);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(b"0\r\n\r\n");
+
+            body
+        } else {
+            _ = write!(response, "Content-Length: {}\r\n\r\n", self.body.len());
+            self.body.clone()
+        };
+
+        stream.write_all(response.as_bytes())?;
+        if head {
+            return Ok(());
+        }
+
+        let (first, rest) = body.split_at(self.delay.map_or(body.len(), |_| body.len() / 2));
+        stream.write_all(first)?;
+        if let Some(delay) = self.delay {
+            stream.flush()?;
+            thread::sleep(delay);
+        }
+
+        stream.write_all(rest)
+    }
+}
+
+//Minimal HTTP/1.1 server for tests, every request is answered by a handler
+pub struct Server {
+    port: u16,
+}
+
+impl Server {
+    pub fn new(handler: impl Fn(&str) -> Response + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock server");
+        let port = listener
+            .local_addr()
+            .expect("Missing mock server address")
+            .port();
+
+        let handler: Arc<Handler> = Arc::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming().map_while(Result::ok) {
+                let handler = handler.clone();
+                thread::spawn(move || Self::serve(stream, &*handler));
+            }
+        });
+
+        Self { port }
+    }
+
+    //Serves whatever the returned text currently holds at every path
+    pub fn text() -> (Self, Arc<Mutex<String>>) {
+        let text = Arc::new(Mutex::new(String::new()));
+        let shared = text.clone();
+        let server = Self::new(move |_| {
+            Response::ok(shared.lock().expect("Mock text lock poisoned").as_str())
+        });
+
+        (server, text)
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        format!("http://127.0.0.1:{}{path}", self.port).into()
+    }
+
+    fn serve(stream: TcpStream, handler: &Handler) {
+        let mut writer = stream.try_clone().expect("Failed to clone mock stream");
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+
+            //Headers are ignored, requests never have a body
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+                line.clear();
+            }
+
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default();
+            let path = parts.next().unwrap_or_default();
+
+            if handler(path).write(&mut writer, method == "HEAD").is_err() {
+                return;
+            }
+        }
+    }
+}
+
+//Media playlist with a segment of 10ms for each path
+pub fn media_playlist(sequence: usize, segments: &[&str]) -> String {
+    let mut playlist =
+        format!("#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:{sequence}\n");
+    for segment in segments {
+        _ = write!(playlist, "#EXTINF:0.010,live\n{segment}\n");
+    }
+
+    playlist
+}
+
+pub fn set(text: &Mutex<String>, value: &str) {
+    *text.lock().expect("Mock text lock poisoned") = value.to_owned();
+}
+
+//Agent without retries, so failures show up right away
+pub fn agent() -> Agent {
+    agent_with(Args {
+        retries: 0,
+        timeout: Duration::from_secs(5),
+        ..Args::default()
+    })
+}
+
+pub fn agent_with_playlist_size(max_playlist_size: usize) -> Agent {
+    agent_with(Args {
+        retries: 0,
+        max_playlist_size,
+        ..Args::default()
+    })
+}
+
+pub fn agent_with(args: Args) -> Agent {
+    Agent::new(args).expect("Failed to create agent")
+}
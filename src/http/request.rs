@@ -1,17 +1,24 @@
 use std::{
+    borrow::Cow,
     fmt::Arguments,
     hash::{DefaultHasher, Hasher},
     io::{self, Read, Write},
     mem,
     net::{SocketAddr, TcpStream, ToSocketAddrs},
     str,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, bail, ensure};
 use log::{debug, error};
 use rustls::{ClientConnection, StreamOwned};
+use socket2::{Domain, Protocol, Socket, Type};
 
-use super::{Agent, Method, Scheme, StatusError, Url, decoder::Decoder, socks5};
+use super::{
+    Agent, Method, Scheme, StatusError, TimeoutError, TooSmallError, Url, WriteError,
+    decoder::Decoder, socks5,
+};
+use crate::metrics;
 
 pub struct Request<W: Write> {
     writer: W,
@@ -24,6 +31,10 @@ pub struct Request<W: Write> {
     decode_buf: Box<[u8]>,
 
     retries: u64,
+    deadline: Option<Instant>,
+    body_limit: Option<usize>,
+    body_min: usize,
+    retried: bool, //whether the last call needed retries
     agent: Agent,
 }
 
@@ -41,6 +52,10 @@ impl<W: Write> Request<W> {
             stream: Option::default(),
             scheme: Scheme::default(),
             host_hash: u64::default(),
+            deadline: Option::default(),
+            body_limit: Option::default(),
+            body_min: usize::default(),
+            retried: bool::default(),
         }
     }
 
@@ -56,11 +71,92 @@ impl<W: Write> Request<W> {
         &mut self.writer
     }
 
+    //Smaller responses are retried and then fail with TooSmallError, without being written
+    pub const fn set_min_body(&mut self, min: usize) {
+        self.body_min = min;
+    }
+
+    pub const fn retries(&self) -> u64 {
+        self.retries
+    }
+
+    pub const fn set_retries(&mut self, retries: u64) {
+        self.retries = retries;
+    }
+
+    pub const fn retried(&self) -> bool {
+        self.retried
+    }
+
     pub fn call(&mut self, method: Method, url: &Url) -> Result<()> {
-        self.call_impl(method, url, None)
+        self.call_impl(method, url, None, None)
+    }
+
+    //Unlike the socket timeout, this limits the time spent on the whole response,
+    //but not the time spent waiting for a free connection
+    pub fn call_timeout(&mut self, method: Method, url: &Url, timeout: Duration) -> Result<()> {
+        let result = self.call_impl(method, url, None, Some(timeout));
+        self.deadline = None;
+
+        if let Some(stream) = &self.stream {
+            stream.set_read_timeout(self.agent.args.timeout)?;
+        }
+
+        result
     }
 
-    fn call_impl(&mut self, method: Method, url: &Url, args: Option<Arguments>) -> Result<()> {
+    fn call_impl(
+        &mut self,
+        method: Method,
+        url: &Url,
+        args: Option<Arguments>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let agent = self.agent.clone();
+        let _permit = agent.connections.acquire();
+        self.deadline = timeout.map(|t| Instant::now() + t);
+        self.retried = false;
+
+        let mut url = Cow::Borrowed(url);
+        let mut redirects = 0;
+        while let Some(location) = self.call_once(method, &url, args)? {
+            url = Cow::Owned(self.redirect(&url, &location, redirects)?);
+            redirects += 1;
+        }
+
+        if redirects > 0 {
+            debug!("Resolved URL after {redirects} redirects: {url}");
+        }
+
+        self.writer.flush().context(WriteError)?;
+        Ok(())
+    }
+
+    fn redirect(&self, url: &Url, location: &str, redirects: u64) -> Result<Url> {
+        ensure!(
+            redirects < self.agent.args.max_redirects,
+            "Too many redirects on {url}, see --max-redirects"
+        );
+
+        let next = url.join(location);
+        ensure!(
+            self.agent.args.allow_downgrade
+                || url.scheme != Scheme::Https
+                || next.scheme != Scheme::Http,
+            "Refusing to follow redirect from HTTPS to HTTP on {url}, see --allow-downgrade"
+        );
+
+        debug!("Redirected to {next}");
+        Ok(next)
+    }
+
+    //Returns the location of a redirect, if any
+    fn call_once(
+        &mut self,
+        method: Method,
+        url: &Url,
+        args: Option<Arguments>,
+    ) -> Result<Option<String>> {
         let host = url.host()?;
         let hash = Self::hash(host);
         if self.stream.is_none() || self.host_hash != hash || self.scheme != url.scheme {
@@ -70,21 +166,36 @@ impl<W: Write> Request<W> {
         let mut retries = 0;
         loop {
             match self.converse(method, host, url, args) {
-                Ok(()) => break,
+                Ok(None) => return Ok(None),
+                Ok(Some(location)) => {
+                    //Redirect bodies aren't read, so the connection can't be reused
+                    self.stream = None;
+                    return Ok(Some(location));
+                }
+                Err(error) if self.deadline.is_some_and(|d| Instant::now() >= d) => {
+                    debug!("http: {error}");
+
+                    //Rest of the response is still pending on the socket
+                    self.stream = None;
+                    return Err(TimeoutError(url.clone()).into());
+                }
                 Err(error) if retries < self.retries && Self::should_retry(&error) => {
                     if retries > 0 {
                         error!("http: {error}, retrying...");
+                        metrics::add_retry();
+                        self.retried = true;
                     }
 
                     retries += 1;
                     self.connect(url, host, hash)?;
                 }
-                Err(e) => return Err(e),
+                Err(e) => {
+                    //Rest of the response may still be pending on the socket
+                    self.stream = None;
+                    return Err(e);
+                }
             }
         }
-
-        self.writer.flush()?;
-        Ok(())
     }
 
     fn converse(
@@ -93,8 +204,8 @@ impl<W: Write> Request<W> {
         host: &str,
         url: &Url,
         args: Option<Arguments>,
-    ) -> Result<()> {
-        let mut stream = self.stream.as_mut().expect("Missing stream while writing");
+    ) -> Result<Option<String>> {
+        let stream = self.stream.as_mut().expect("Missing stream while writing");
         write!(
             stream,
             "{method} /{path} HTTP/1.1\r\n\
@@ -102,7 +213,7 @@ impl<W: Write> Request<W> {
              User-Agent: {user_agent}\r\n\
              Accept: */*\r\n\
              Accept-Language: en-US\r\n\
-             Accept-Encoding: gzip\r\n\
+             Accept-Encoding: gzip, deflate\r\n\
              Connection: keep-alive\r\n\
              {args}",
             path = url.path()?,
@@ -111,9 +222,15 @@ impl<W: Write> Request<W> {
         )?;
         stream.flush()?;
 
+        let mut stream = DeadlineReader {
+            inner: stream,
+            deadline: self.deadline,
+            timeout: self.agent.args.timeout,
+        };
+
         //Read response headers and separate headers from body if needed
         let mut written = 0;
-        let (headers, body) = loop {
+        let (headers, body, location) = loop {
             let read = stream.read(&mut self.headers_buf[written..])?;
             if read == 0 {
                 return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
@@ -128,8 +245,10 @@ impl<W: Write> Request<W> {
                     self.headers_buf[..written].split_at_mut_checked(p + 4 /* pass \r\n\r\n */)
                 })
             {
+                //Location is case sensitive
+                let location = Self::location(headers);
                 headers.make_ascii_lowercase();
-                break (str::from_utf8(headers)?, body);
+                break (str::from_utf8(headers)?, body, location);
             }
         };
         debug!("Response:\n{headers}");
@@ -140,6 +259,12 @@ impl<W: Write> Request<W> {
             .and_then(|s| s.parse().ok())
             .context("Failed to parse HTTP status code")?;
 
+        if matches!(code, 301 | 302 | 303 | 307 | 308)
+            && let Some(location) = location
+        {
+            return Ok(Some(location));
+        }
+
         if code != 200 {
             return Err(StatusError(code, url.clone()).into());
         }
@@ -147,19 +272,53 @@ impl<W: Write> Request<W> {
         match method {
             Method::Get | Method::Post => {
                 let mut decoder = Decoder::new(body.chain(&mut stream), headers)?;
+                let mut total = 0;
+                let mut held = Vec::new(); //not written until the minimum size is reached
                 loop {
                     let read = decoder.read(&mut self.decode_buf)?;
                     if read == 0 {
-                        break Ok(());
+                        if total < self.body_min {
+                            return Err(TooSmallError(total, url.clone()).into());
+                        }
+
+                        break Ok(None);
+                    }
+
+                    total += read;
+                    if let Some(limit) = self.body_limit {
+                        ensure!(
+                            total <= limit,
+                            "Response from {url} exceeded --max-playlist-size of {limit} bytes"
+                        );
+                    }
+
+                    if total < self.body_min {
+                        held.extend_from_slice(&self.decode_buf[..read]);
+                        continue;
                     }
 
-                    self.writer.write_all(&self.decode_buf[..read])?;
+                    if !held.is_empty() {
+                        self.writer.write_all(&held).context(WriteError)?;
+                        held.clear();
+                    }
+
+                    self.writer
+                        .write_all(&self.decode_buf[..read])
+                        .context(WriteError)?;
                 }
             }
-            Method::Head => Ok(()),
+            Method::Head => Ok(None),
         }
     }
 
+    fn location(headers: &[u8]) -> Option<String> {
+        str::from_utf8(headers).ok()?.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_owned())
+        })
+    }
+
     fn connect(&mut self, url: &Url, host: &str, host_hash: u64) -> Result<()> {
         self.stream = Some(Transport::new(url, host, &self.agent)?);
         self.scheme = url.scheme;
@@ -175,12 +334,14 @@ impl<W: Write> Request<W> {
         hasher.finish()
     }
 
-    //Retry if not 404 or io::ErrorKind::Other (used for internal errors)
+    //Retry if too small, or if not 404, a writer error, or io::ErrorKind::Other (used for internal errors)
     fn should_retry(error: &anyhow::Error) -> bool {
         error.is::<StatusError>() && !StatusError::is_not_found(error)
-            || error
-                .downcast_ref::<io::Error>()
-                .is_some_and(|e| e.kind() != io::ErrorKind::Other)
+            || error.is::<TooSmallError>()
+            || !error.is::<WriteError>()
+                && error
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() != io::ErrorKind::Other)
     }
 }
 
@@ -188,7 +349,10 @@ pub struct TextRequest(Request<StringWriter>);
 
 impl TextRequest {
     pub fn new(agent: Agent) -> Self {
-        Self(Request::new(StringWriter::default(), agent))
+        let mut request = Request::new(StringWriter::default(), agent);
+        request.body_limit = Some(request.agent.args.max_playlist_size);
+
+        Self(request)
     }
 
     pub fn take(&mut self) -> String {
@@ -215,12 +379,33 @@ impl TextRequest {
 
     fn text_impl(&mut self, method: Method, url: &Url, data: Option<Arguments>) -> Result<&str> {
         self.0.writer.0.clear();
-        self.0.call_impl(method, url, data)?;
+        self.0.call_impl(method, url, data, None)?;
 
         Ok(&self.0.writer.0)
     }
 }
 
+struct DeadlineReader<'a> {
+    inner: &'a mut Transport,
+    deadline: Option<Instant>,
+    timeout: Duration,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+
+            self.inner.set_read_timeout(remaining.min(self.timeout))?;
+        }
+
+        self.inner.read(buf)
+    }
+}
+
 enum Transport {
     Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
     Unencrypted(TcpStream),
@@ -291,15 +476,19 @@ impl Transport {
         }
     }
 
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Self::Tls(stream) => stream.get_ref().set_read_timeout(Some(timeout)),
+            Self::Unencrypted(sock) => sock.set_read_timeout(Some(timeout)),
+        }
+    }
+
     fn connect(addrs: &[SocketAddr], agent: &Agent) -> Result<TcpStream> {
         ensure!(!addrs.is_empty(), "Failed to resolve socket address");
 
         let mut io_error = None;
-        for addr in addrs
-            .iter()
-            .filter(|a| !agent.args.force_ipv4 || SocketAddr::is_ipv4(a))
-        {
-            match TcpStream::connect_timeout(addr, agent.args.timeout) {
+        for addr in addrs.iter().filter(|a| agent.args.ip_version.matches(a)) {
+            match Self::connect_addr(addr, agent) {
                 Ok(sock) => {
                     sock.set_nodelay(true)?;
                     sock.set_read_timeout(Some(agent.args.timeout))?;
@@ -311,9 +500,26 @@ impl Transport {
             }
         }
 
-        Err(io_error
-            .expect("Missing IO error while connection failed")
-            .into())
+        match io_error {
+            Some(error) => Err(error.into()),
+            None => bail!("No address found matching the configured IP version"),
+        }
+    }
+
+    fn connect_addr(addr: &SocketAddr, agent: &Agent) -> io::Result<TcpStream> {
+        let Some(bind) = agent.args.bind else {
+            return TcpStream::connect_timeout(addr, agent.args.timeout);
+        };
+
+        let sock = Socket::new(
+            Domain::for_address(*addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        sock.bind(&SocketAddr::new(bind, 0).into())?;
+        sock.connect_timeout(&(*addr).into(), agent.args.timeout)?;
+
+        Ok(sock.into())
     }
 }
 
@@ -338,3 +544,147 @@ impl Write for StringWriter {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{
+        Args,
+        mock::{self, Response, Server},
+    };
+
+    //Every /hop/<n> redirects to /hop/<n - 1>, alternating absolute and relative locations
+    fn hops_server() -> Server {
+        Server::new(
+            |path| match path.strip_prefix("/hop/").map(str::parse::<u64>) {
+                Some(Ok(0)) => Response::ok("done"),
+                Some(Ok(n)) if n % 2 == 0 => Response::redirect(302, &format!("/hop/{}", n - 1)),
+                Some(Ok(n)) => Response::redirect(301, &(n - 1).to_string()),
+                _ => Response::status(404),
+            },
+        )
+    }
+
+    #[test]
+    fn redirect_chain_is_followed() -> Result<()> {
+        let server = hops_server();
+        let mut request = mock::agent().text();
+
+        assert_eq!(request.text(Method::Get, &server.url("/hop/4"))?, "done");
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_redirects() {
+        let server = hops_server();
+        let mut request = mock::agent_with(Args {
+            retries: 0,
+            max_redirects: 3,
+            ..Args::default()
+        })
+        .text();
+
+        assert!(request.text(Method::Get, &server.url("/hop/3")).is_ok());
+        let error = request
+            .text(Method::Get, &server.url("/hop/4"))
+            .expect_err("Followed more than --max-redirects");
+        assert!(error.to_string().starts_with("Too many redirects"));
+    }
+
+    #[test]
+    fn https_to_http_is_refused() -> Result<()> {
+        let url = Url::from("https://example.com/a/b");
+        let request = mock::agent().text();
+
+        let error = request
+            .0
+            .redirect(&url, "http://example.com/c", 0)
+            .expect_err("Followed a redirect from HTTPS to HTTP");
+        assert!(error.to_string().starts_with("Refusing to follow redirect"));
+        assert_eq!(
+            *request.0.redirect(&url, "https://example.com/c", 0)?,
+            "https://example.com/c"
+        );
+
+        let request = mock::agent_with(Args {
+            allow_downgrade: true,
+            ..Args::default()
+        })
+        .text();
+        assert_eq!(
+            *request.0.redirect(&url, "http://example.com/c", 0)?,
+            "http://example.com/c"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn small_bodies_are_not_written() -> Result<()> {
+        let server = Server::new(|path| match path {
+            "/empty" => Response::ok(""),
+            "/small" => Response::ok([0; 187]),
+            "/small-chunked" => Response::ok([0; 187]).chunked(),
+            _ => Response::ok([0; 188]).chunked(),
+        });
+
+        for path in ["/empty", "/small", "/small-chunked"] {
+            let mut request = mock::agent().binary(Vec::new());
+            request.set_min_body(188);
+
+            let error = request
+                .call(Method::Get, &server.url(path))
+                .expect_err("Accepted a body below the minimum size");
+            assert!(error.is::<TooSmallError>(), "{path}");
+            assert!(request.get_ref().is_empty(), "{path}");
+        }
+
+        let mut request = mock::agent().binary(Vec::new());
+        request.set_min_body(188);
+        request.call(Method::Get, &server.url("/minimum"))?;
+        assert_eq!(request.get_ref().len(), 188);
+        Ok(())
+    }
+
+    #[test]
+    fn timeout_starts_with_the_connection() -> Result<()> {
+        let server = Server::new(|path| Response::ok(path));
+        let agent = mock::agent_with(Args {
+            retries: 0,
+            max_connections: 1,
+            ..Args::default()
+        });
+
+        //Holds the only connection for longer than the timeout
+        let (held, release) = std::sync::mpsc::channel();
+        let holder = agent.clone();
+        let handle = std::thread::spawn(move || {
+            let _permit = holder.connections.acquire();
+            _ = held.send(());
+            std::thread::sleep(Duration::from_millis(300));
+        });
+        _ = release.recv();
+
+        let mut request = agent.binary(Vec::new());
+        request.call_timeout(Method::Get, &server.url("/s0"), Duration::from_millis(200))?;
+        assert_eq!(request.get_ref(), b"/s0");
+
+        handle.join().expect("Permit holder panicked");
+        Ok(())
+    }
+
+    #[test]
+    fn relative_location_is_resolved() -> Result<()> {
+        let server = Server::new(|path| match path {
+            "/a/b?token=1" => Response::redirect(307, "c/d"),
+            "/a/c/d" => Response::ok("done"),
+            _ => Response::status(404),
+        });
+        let mut request = mock::agent().text();
+
+        assert_eq!(
+            request.text(Method::Get, &server.url("/a/b?token=1"))?,
+            "done"
+        );
+        Ok(())
+    }
+}
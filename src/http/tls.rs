@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use log::{debug, error, warn};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+
+use super::Args;
+
+pub fn config(args: &Args) -> Result<ClientConfig> {
+    if args.insecure {
+        warn!("--insecure is set, TLS certificates will NOT be verified!");
+        return Ok(ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification::new()))
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    let res = rustls_native_certs::load_native_certs();
+
+    for error in res.errors {
+        error!("Failed to load certificates: {error}");
+    }
+
+    for cert in res.certs {
+        //Ignore parsing errors, OS can have broken certs
+        if let Err(e) = roots.add(cert) {
+            debug!("Invalid certificate: {e}");
+        }
+    }
+
+    if let Some(path) = &args.cafile {
+        let mut count = 0;
+        for cert in CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("Failed to open --cafile {path}"))?
+        {
+            let cert = cert.with_context(|| format!("Failed to parse --cafile {path}"))?;
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid certificate in --cafile {path}"))?;
+
+            count += 1;
+        }
+
+        debug!("Loaded {count} certificates from {path}");
+    }
+
+    Ok(ClientConfig::builder()
+        .with_root_certificates(Arc::new(roots))
+        .with_no_client_auth())
+}
+
+//Accepts any certificate, signatures are still checked so the handshake itself is sound
+#[derive(Debug)]
+struct NoVerification {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl NoVerification {
+    fn new() -> Self {
+        Self {
+            algorithms: crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    borrow::Cow,
     convert::Infallible,
     fmt::{self, Display, Formatter},
     ops::Deref,
@@ -67,6 +68,25 @@ impl Url {
         Ok(host.split_once(':').map_or(host, |(s, _)| s))
     }
 
+    //Host with the port, if any
+    pub fn authority(&self) -> Result<&str> {
+        self.inner
+            .split_terminator('/')
+            .nth(2)
+            .context("Failed to parse host in URL")
+    }
+
+    //Same path and query on another host
+    pub fn with_authority(&self, authority: &str) -> Result<Self> {
+        let (scheme, rest) = self
+            .inner
+            .split_once("//")
+            .context("Failed to parse host in URL")?;
+        let path = rest.find(['/', '?', '#']).map_or("", |i| &rest[i..]);
+
+        Ok(format!("{scheme}//{authority}{path}").into())
+    }
+
     pub fn path(&self) -> Result<&str> {
         self.inner
             .splitn(4, '/')
@@ -74,6 +94,53 @@ impl Url {
             .context("Failed to parse path in URL")
     }
 
+    //Hides the values of query parameters that look like credentials
+    pub fn redacted(&self) -> Cow<'_, str> {
+        let Some((base, query)) = self.inner.split_once('?') else {
+            return Cow::Borrowed(&self.inner);
+        };
+
+        let query = query
+            .split('&')
+            .map(|param| match param.split_once('=') {
+                Some((name, _)) if is_secret(name) => Cow::Owned(format!("{name}=<hidden>")),
+                _ => Cow::Borrowed(param),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+
+        Cow::Owned(format!("{base}?{query}"))
+    }
+
+    //Resolves a redirect location relative to this URL
+    pub fn join(&self, location: &str) -> Self {
+        if Scheme::new(location) != Scheme::Unknown {
+            return location.into();
+        }
+
+        if let Some(location) = location.strip_prefix("//") {
+            return format!("{}://{location}", self.scheme).into();
+        }
+
+        let authority_start = self.inner.find("//").map_or(0, |i| i + 2);
+        let origin_len = self.inner[authority_start..]
+            .find(['/', '?', '#'])
+            .map_or(self.inner.len(), |i| authority_start + i);
+        let origin = &self.inner[..origin_len];
+        if location.starts_with('/') {
+            return format!("{origin}{location}").into();
+        }
+
+        //Relative to the directory of the current path, without the query
+        let path = self.inner.split(['?', '#']).next().unwrap_or_default();
+        let dir = path
+            .rfind('/')
+            .filter(|i| *i >= origin_len)
+            .map_or_else(|| format!("{origin}/"), |i| path[..=i].to_owned());
+
+        format!("{dir}{location}").into()
+    }
+
     pub fn port(&self) -> Result<u16> {
         if let Some(port) = self
             .inner
@@ -92,6 +159,11 @@ impl Url {
     }
 }
 
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.contains("token") || name.contains("auth") || name == "sig" || name == "signature"
+}
+
 #[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Scheme {
     Http,
@@ -120,3 +192,29 @@ impl Scheme {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join() {
+        let url = Url::from("https://host:8080/dir/file.m3u8?query=/x#frag");
+        for (location, expected) in [
+            ("http://other/path", "http://other/path"),
+            ("//other/path", "https://other/path"),
+            ("/root", "https://host:8080/root"),
+            ("sibling.ts", "https://host:8080/dir/sibling.ts"),
+            ("sub/seg.ts?a=b", "https://host:8080/dir/sub/seg.ts?a=b"),
+        ] {
+            assert_eq!(*url.join(location), expected, "{location}");
+        }
+
+        let url = Url::from("http://host");
+        assert_eq!(*url.join("/root"), "http://host/root");
+        assert_eq!(*url.join("file"), "http://host/file");
+
+        let url = Url::from("http://host?query=/x");
+        assert_eq!(*url.join("file"), "http://host/file");
+    }
+}
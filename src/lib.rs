@@ -0,0 +1,34 @@
+//! Minimal client for watching/recording Twitch streams.
+//!
+//! The CLI is a thin wrapper around [`Session`], which can be embedded to write
+//! a stream to custom [`Output`]s alongside the ones configured by arguments:
+//!
+//! ```no_run
+//! use twitch_hls_client::{Config, Session};
+//!
+//! let config = Config::from_args(["--no-config", "-r", "recording.ts", "channel", "best"])?;
+//! Session::new(config).run(Vec::new())?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Options are the same as the CLI's, see `--help`.
+//!
+//! The `testing` feature adds `Memory`, an output that records every call
+//! so the download loop can be tested end to end against a mock server.
+
+mod args;
+mod constants;
+mod heartbeat;
+mod hls;
+mod http;
+mod logger;
+mod metrics;
+mod output;
+mod session;
+
+pub use args::HelpError;
+pub use hls::OfflineError;
+#[cfg(feature = "testing")]
+pub use output::{Event, Memory};
+pub use output::{Output, SegmentStats};
+pub use session::{Config, Session, exit_code};
@@ -4,12 +4,40 @@ use std::{
     time::SystemTime,
 };
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 use log::{Level, LevelFilter, Log, Metadata, Record};
 
+#[derive(Default, Copy, Clone, Debug)]
+pub enum Color {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    pub fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => bail!("Invalid color mode"),
+        }
+    }
+
+    fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            Self::Auto => env::var_os("NO_COLOR").is_none() && is_terminal,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
 pub struct Logger {
     enable_debug: bool,
-    enable_colors: bool,
+    stdout_colors: bool,
+    stderr_colors: bool,
 }
 
 impl Log for Logger {
@@ -20,7 +48,7 @@ impl Log for Logger {
     fn log(&self, record: &Record<'_>) {
         let level = record.level();
         match level {
-            Level::Error | Level::Info | Level::Debug if self.enable_debug => {
+            _ if self.enable_debug => {
                 let thread = std::thread::current();
                 println!(
                     "{time} {tag} ({thread}) {module}: {log}",
@@ -28,13 +56,15 @@ impl Log for Logger {
                         .duration_since(SystemTime::UNIX_EPOCH)
                         .unwrap_or_default()
                         .as_millis(),
-                    tag = level_tag(level, self.enable_colors),
+                    tag = level_tag(level, self.stdout_colors),
                     thread = thread.name().unwrap_or("<unknown>"),
                     module = record.module_path().unwrap_or("<unknown>"),
                     log = record.args(),
                 );
             }
-            Level::Error => eprintln!("{} {}", level_tag(level, self.enable_colors), record.args()),
+            Level::Error | Level::Warn => {
+                eprintln!("{} {}", level_tag(level, self.stderr_colors), record.args());
+            }
             Level::Info => println!("{}", record.args()),
             _ => (),
         }
@@ -44,16 +74,19 @@ impl Log for Logger {
 }
 
 impl Logger {
-    pub fn init(enable_debug: bool) -> Result<()> {
+    //0 = info, 1 = debug, 2+ = trace, quiet = errors only
+    pub fn init(verbosity: u8, quiet: bool, color: Color) -> Result<()> {
         log::set_boxed_logger(Box::new(Self {
-            enable_debug,
-            enable_colors: env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal(),
+            enable_debug: verbosity > 0,
+            stdout_colors: color.enabled(io::stdout().is_terminal()),
+            stderr_colors: color.enabled(io::stderr().is_terminal()),
         }))?;
 
-        log::set_max_level(if enable_debug {
-            LevelFilter::Debug
-        } else {
-            LevelFilter::Info
+        log::set_max_level(match verbosity {
+            _ if quiet => LevelFilter::Error,
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
         });
 
         Ok(())
@@ -61,25 +94,27 @@ impl Logger {
 }
 
 pub fn is_debug() -> bool {
-    log::max_level() == LevelFilter::Debug
+    log::max_level() >= LevelFilter::Debug
 }
 
-fn level_tag_no_color(level: Level) -> &'static str {
+const fn level_tag_no_color(level: Level) -> &'static str {
     match level {
         Level::Error => "[ERROR]",
+        Level::Warn => "[WARN]",
         Level::Info => "[INFO]",
         Level::Debug => "[DEBUG]",
-        _ => unreachable!(),
+        Level::Trace => "[TRACE]",
     }
 }
 
-fn level_tag(level: Level, enable_colors: bool) -> &'static str {
+const fn level_tag(level: Level, enable_colors: bool) -> &'static str {
     if enable_colors {
         match level {
             Level::Error => "\x1b[31m[ERROR]\x1b[0m", //red
+            Level::Warn => "\x1b[33m[WARN]\x1b[0m",   //yellow
             Level::Info => "\x1b[34m[INFO]\x1b[0m",   //blue
             Level::Debug => "\x1b[36m[DEBUG]\x1b[0m", //cyan
-            _ => unreachable!(),
+            Level::Trace => "\x1b[35m[TRACE]\x1b[0m", //magenta
         }
     } else {
         level_tag_no_color(level)
@@ -0,0 +1,246 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread::Builder as ThreadBuilder,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+
+const QUEUES: [&str; 3] = ["player", "tcp", "record"];
+
+//Updated from anywhere, only read when the endpoint is enabled
+static METRICS: Metrics = Metrics {
+    segments: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+    retries: AtomicU64::new(0),
+    reconnects: AtomicU64::new(0),
+    ads_skipped: AtomicU64::new(0),
+    paused: AtomicBool::new(false),
+    queued: [const { AtomicU64::new(0) }; QUEUES.len()],
+    edge_latency: AtomicU64::new(f64::NAN.to_bits()),
+    quality: Mutex::new(String::new()),
+};
+
+struct Metrics {
+    segments: AtomicU64,
+    bytes: AtomicU64,
+    retries: AtomicU64,
+    reconnects: AtomicU64,
+    ads_skipped: AtomicU64,
+    paused: AtomicBool,
+    queued: [AtomicU64; QUEUES.len()], //segments waiting in each output queue
+    edge_latency: AtomicU64,           //f64 seconds, NaN when unknown
+    quality: Mutex<String>,
+}
+
+pub fn add_segment(bytes: u64) {
+    METRICS.segments.fetch_add(1, Ordering::Relaxed);
+    METRICS.bytes.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn add_retry() {
+    METRICS.retries.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn add_reconnect() {
+    METRICS.reconnects.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn add_ads_skipped(count: u64) {
+    METRICS.ads_skipped.fetch_add(count, Ordering::Relaxed);
+}
+
+pub fn set_paused(paused: bool) {
+    METRICS.paused.store(paused, Ordering::Relaxed);
+}
+
+pub fn push_queued(output: &str) {
+    if let Some(queued) = queued(output) {
+        queued.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn pop_queued(output: &str) {
+    if let Some(queued) = queued(output) {
+        queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn queued(output: &str) -> Option<&'static AtomicU64> {
+    let index = QUEUES.iter().position(|q| *q == output)?;
+    Some(&METRICS.queued[index])
+}
+
+pub fn set_edge_latency(latency: Option<Duration>) {
+    let secs = latency.map_or(f64::NAN, |l| l.as_secs_f64());
+    METRICS
+        .edge_latency
+        .store(secs.to_bits(), Ordering::Relaxed);
+}
+
+pub fn set_quality(quality: &str) {
+    quality.clone_into(&mut METRICS.quality.lock().expect("Metrics lock poisoned"));
+}
+
+//Serves the Prometheus text exposition format on /metrics
+pub fn spawn(addr: &str) -> Result<()> {
+    //Allow ":9102" to listen on every interface
+    let addr = if addr.starts_with(':') {
+        format!("0.0.0.0{addr}")
+    } else {
+        addr.to_owned()
+    };
+
+    let listener = TcpListener::bind(
+        addr.to_socket_addrs()?
+            .next()
+            .context("Failed to resolve metrics address")?,
+    )
+    .context("Failed to bind metrics listener")?;
+
+    info!("Serving metrics on: http://{addr}/metrics");
+    ThreadBuilder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = serve(stream) {
+                    debug!("Metrics client error: {e}");
+                }
+            }
+        })
+        .context("Failed to spawn metrics thread")?;
+
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    //Headers are ignored
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len(),
+    )?;
+
+    stream.flush()
+}
+
+fn render() -> String {
+    const PREFIX: &str = "twitch_hls_client";
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+        _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+        _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+        _ = writeln!(out, "{PREFIX}_{name} {value}");
+    };
+
+    let counters = [
+        (
+            "segments_downloaded_total",
+            "Segments written to outputs",
+            &METRICS.segments,
+        ),
+        (
+            "bytes_written_total",
+            "Bytes written to outputs",
+            &METRICS.bytes,
+        ),
+        (
+            "http_retries_total",
+            "Retried HTTP requests",
+            &METRICS.retries,
+        ),
+        (
+            "reconnects_total",
+            "Reconnect attempts after network failures",
+            &METRICS.reconnects,
+        ),
+        (
+            "ad_segments_skipped_total",
+            "Filtered ad segments",
+            &METRICS.ads_skipped,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        metric(name, "counter", help, &counter.load(Ordering::Relaxed));
+    }
+
+    metric(
+        "paused",
+        "gauge",
+        "Whether recording is paused by SIGUSR2 or --pause-file",
+        &u8::from(METRICS.paused.load(Ordering::Relaxed)),
+    );
+
+    let latency = f64::from_bits(METRICS.edge_latency.load(Ordering::Relaxed));
+    if !latency.is_nan() {
+        metric(
+            "edge_latency_seconds",
+            "gauge",
+            "Time between the end of the newest segment and now",
+            &latency,
+        );
+    }
+
+    _ = writeln!(
+        out,
+        "# HELP {PREFIX}_output_queue_segments Segments waiting to be written by output queues"
+    );
+    _ = writeln!(out, "# TYPE {PREFIX}_output_queue_segments gauge");
+    for (output, queued) in QUEUES.iter().zip(&METRICS.queued) {
+        _ = writeln!(
+            out,
+            "{PREFIX}_output_queue_segments{{output=\"{output}\"}} {}",
+            queued.load(Ordering::Relaxed)
+        );
+    }
+
+    let quality = METRICS
+        .quality
+        .lock()
+        .expect("Metrics lock poisoned")
+        .clone();
+    if !quality.is_empty() {
+        _ = writeln!(
+            out,
+            "# HELP {PREFIX}_quality_info Currently selected quality"
+        );
+        _ = writeln!(out, "# TYPE {PREFIX}_quality_info gauge");
+        _ = writeln!(
+            out,
+            "{PREFIX}_quality_info{{quality=\"{}\"}} 1",
+            quality.replace('\\', r"\\").replace('"', "\\\"")
+        );
+    }
+
+    out
+}
@@ -1,40 +1,131 @@
+mod bitrate_log;
 mod file;
+mod manifest;
+#[cfg(any(test, feature = "testing"))]
+mod memory;
 mod player;
+mod queue;
 mod tcp;
 
+pub use file::RecordingFinishedError;
+#[cfg(any(test, feature = "testing"))]
+pub use memory::{Event, Memory};
 pub use player::{Player, PlayerClosedError};
 
 use std::{
     io::{self, Write},
-    sync::mpsc::channel,
+    mem,
+    time::Duration,
 };
 
-use anyhow::{Result, ensure};
+use anyhow::{Result, bail, ensure};
 use log::{debug, info};
 
-use file::{Args as FileArgs, File};
+use bitrate_log::BitrateLog;
+use file::{Args as FileArgs, File, RecordedHook};
 use player::Args as PlayerArgs;
+use queue::{Args as QueueArgs, Queued};
 use tcp::{Args as TcpArgs, Tcp};
 
-use crate::args::{Parse, Parser};
+use crate::{
+    args::{Parse, Parser},
+    constants, metrics,
+};
+
+/// Passed to [`Output::segment_stats`] after every segment.
+#[derive(Default, Copy, Clone, Debug)]
+pub struct SegmentStats {
+    pub sequence: usize,
+    pub duration: Option<Duration>, //missing for prefetch segments
+    pub bytes: u64,
+    pub total: Duration, //content written so far including this segment
+}
 
+/// Destination for the stream's MPEG-TS/fMP4 data.
+///
+/// Each segment is written with [`Write::write_all`] followed by [`Write::flush`].
+/// Returning an error from any method removes the output, and stops the session
+/// if it was the last one.
+#[allow(clippy::missing_errors_doc)] //see above
 pub trait Output: Write + Send {
+    /// Called with the initialization segment of fMP4 streams, before any segment.
     fn set_header(&mut self, header: &[u8]) -> io::Result<()>;
 
+    /// Called instead of [`Write::flush`] when a segment failed after part of it was written.
+    ///
+    /// Outputs that can should drop the partial segment, a discontinuity follows.
+    fn abort_segment(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called after a segment has been completely written.
+    fn segment_stats(&mut self, _stats: &SegmentStats) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called when the quality is first selected and when it's switched mid-stream.
+    fn set_quality(&mut self, _quality: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called in place of an ad segment with the last content segment under `--ads freeze`.
+    ///
+    /// Only the player writes it again, other outputs skip ads as usual.
+    fn freeze(&mut self, _segment: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called when segments were skipped or the stream was resumed after a failure.
+    fn discontinuity(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called when the playlist signals that the stream ended, after the last segment.
+    fn stream_ended(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether downloading should pause until [`Output::wait_for_output`] returns.
     fn should_wait(&self) -> bool {
         false
     }
 
+    /// Blocks until the output is ready to be written to again.
     fn wait_for_output(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
 
-#[derive(Default, Debug)]
+//Overrides how the stream is treated instead of going by the playlist and recording path
+#[derive(Copy, Clone, Debug)]
+pub enum Container {
+    Ts,
+    Mp4,
+    Raw,
+}
+
+impl Container {
+    fn new(arg: &str) -> Result<Option<Self>> {
+        match arg {
+            "ts" => Ok(Some(Self::Ts)),
+            "mp4" => Ok(Some(Self::Mp4)),
+            "raw" => Ok(Some(Self::Raw)),
+            _ => bail!("Invalid container, must be ts, mp4 or raw"),
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
 pub struct Args {
     pub player: PlayerArgs,
     tcp: TcpArgs,
     file: FileArgs,
+    bitrate_log: Option<String>,
+    player_queue: QueueArgs,
+    tcp_queue: QueueArgs,
+    record_queue: QueueArgs,
+    container: Option<Container>,
+    no_header: bool,
 }
 
 impl Parse for Args {
@@ -42,6 +133,33 @@ impl Parse for Args {
         self.player.parse(parser)?;
         self.tcp.parse(parser)?;
         self.file.parse(parser)?;
+        parser.parse_opt(&mut self.bitrate_log, "--record-bitrate-log")?;
+        self.player_queue
+            .parse(parser, "--player-queue", "--player-queue-policy")?;
+        self.tcp_queue
+            .parse(parser, "--tcp-queue", "--tcp-queue-policy")?;
+        self.record_queue
+            .parse(parser, "--record-queue", "--record-queue-policy")?;
+
+        parser.parse_fn(&mut self.container, "--container", Container::new)?;
+        self.file.container = self.container;
+        parser.parse_switch(&mut self.no_header, "--no-header")?;
+
+        Ok(())
+    }
+}
+
+impl Args {
+    //Recording several streams at once only works with separate files
+    pub fn ensure_record_only(&self) -> Result<()> {
+        ensure!(
+            self.file.is_enabled() && !self.player.is_enabled() && !self.tcp.is_enabled(),
+            "Multiple qualities can only be recorded with -r"
+        );
+        ensure!(
+            !self.file.has_shared_files() && self.bitrate_log.is_none(),
+            "Multiple qualities cannot be used with --record-concat-list, --record-latest-link, --record-manifest or --record-bitrate-log"
+        );
 
         Ok(())
     }
@@ -50,14 +168,80 @@ impl Parse for Args {
 #[derive(Default)]
 pub struct Writer {
     outputs: Vec<Box<dyn Output>>,
+    bitrate_log: Option<BitrateLog>,
+    stats: SegmentStats,
+    last_duration: Duration,
+    ads: Option<File>,
+    is_ad: bool,
+    container: Option<Container>,
+    no_header: bool,
+    freeze: bool,
+    freeze_current: Vec<u8>, //content segment being written
+    freeze_last: Vec<u8>,    //last complete content segment
+    on_recorded: Option<RecordedHook>,
 }
 
 impl Output for Writer {
     fn set_header(&mut self, header: &[u8]) -> io::Result<()> {
+        if matches!(self.container, Some(Container::Ts)) {
+            debug!("Ignoring segment header for --container ts");
+            return Ok(());
+        }
+
+        if self.no_header {
+            debug!("Ignoring segment header for --no-header");
+            return Ok(());
+        }
+
         debug!("Outputting segment header");
+        if let Some(ads) = &mut self.ads {
+            ads.set_header(header)?;
+        }
+
         self.handle_outputs(|output| output.set_header(header))
     }
 
+    fn set_quality(&mut self, quality: &str) -> io::Result<()> {
+        debug!("Selected quality: {quality}");
+        metrics::set_quality(quality);
+        if let Some(ads) = &mut self.ads {
+            ads.set_quality(quality)?;
+        }
+
+        self.handle_outputs(|output| output.set_quality(quality))
+    }
+
+    fn abort_segment(&mut self) -> io::Result<()> {
+        if self.stats.bytes == 0 {
+            return Ok(());
+        }
+
+        debug!(
+            "Discarding {} bytes of incomplete segment",
+            self.stats.bytes
+        );
+        self.stats.bytes = 0;
+        if self.is_ad
+            && let Some(ads) = &mut self.ads
+        {
+            return ads.abort_segment();
+        }
+
+        self.freeze_current.clear();
+        self.handle_outputs(|output| output.abort_segment())
+    }
+
+    fn discontinuity(&mut self) -> io::Result<()> {
+        debug!("Signaling discontinuity");
+        self.freeze_current.clear();
+        self.handle_outputs(|output| output.discontinuity())
+    }
+
+    fn stream_ended(&mut self) -> io::Result<()> {
+        debug!("Signaling end of stream");
+        self.handle_outputs(|output| output.stream_ended())
+    }
+
     fn should_wait(&self) -> bool {
         if self.outputs.len() == 1
             && let Some(output) = self.outputs.first()
@@ -84,33 +268,162 @@ impl Write for Writer {
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        if self.is_ad
+            && let Some(ads) = &mut self.ads
+        {
+            debug!("Finished writing ad segment");
+            ads.flush()?;
+
+            return self.freeze();
+        }
+
         self.handle_outputs(Write::flush)?;
+        if self.freeze {
+            mem::swap(&mut self.freeze_current, &mut self.freeze_last);
+            self.freeze_current.clear();
+        }
+
+        //Prefetch segments don't have a duration yet, assume it's the same as the last one
+        if let Some(duration) = self.stats.duration {
+            self.last_duration = duration;
+        }
+        self.stats.total += self.last_duration;
+
+        let stats = self.stats;
+        metrics::add_segment(stats.bytes);
+        self.handle_outputs(|output| output.segment_stats(&stats))?;
+        if let Some(bitrate_log) = &mut self.bitrate_log {
+            bitrate_log.log(&stats)?;
+        }
 
         debug!("Finished writing segment");
         Ok(())
     }
 
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.stats.bytes += buf.len() as u64;
+        if self.is_ad
+            && let Some(ads) = &mut self.ads
+        {
+            return ads.write_all(buf);
+        }
+
+        if self.freeze {
+            self.freeze_current.extend_from_slice(buf);
+        }
+
         self.handle_outputs(|output| output.write_all(buf))
     }
 }
 
 impl Writer {
-    pub fn new(args: &Args, channel: &str) -> Result<Self> {
+    pub fn new(args: &Args, channel: &str, extra: Vec<Box<dyn Output>>) -> Result<Self> {
         let mut writer = Self::default();
 
-        writer.add_output(Player::new(&args.player)?);
-        writer.add_output(Tcp::new(&args.tcp)?);
-        writer.add_output(File::new(&args.file, channel)?);
+        //Disk writes can block for a while, so they never happen on the download thread
+        let record_queue = args.record_queue.always(constants::RECORD_QUEUE_DEPTH);
+
+        //Pacing sleeps between segments, which would stall downloading without a queue
+        let player_queue = if args.player.is_paced() {
+            args.player_queue.always(constants::PLAYER_PACE_QUEUE_DEPTH)
+        } else {
+            args.player_queue.clone()
+        };
 
-        ensure!(!writer.outputs.is_empty(), "No output configured");
+        let mut outputs = Vec::new();
+        Self::add_output(
+            &mut outputs,
+            Player::new(&args.player, channel)?,
+            &player_queue,
+            "player",
+        );
+        Self::add_output(&mut outputs, Tcp::new(&args.tcp)?, &args.tcp_queue, "tcp");
+
+        let file = File::new(&args.file, channel)?;
+        writer.on_recorded = file.as_ref().map(File::on_recorded);
+        Self::add_output(&mut outputs, file, &record_queue, "record");
+
+        ensure!(
+            !outputs.is_empty() || !extra.is_empty(),
+            "No output configured"
+        );
+
+        //Queues only make sense when there's other outputs to keep going
+        let has_multiple = outputs.len() + extra.len() > 1;
+        for (output, queue, name) in outputs {
+            if queue.is_enabled() && (has_multiple || queue.is_always()) {
+                writer
+                    .outputs
+                    .push(Box::new(Queued::spawn(output, queue, name)?));
+            } else {
+                writer.outputs.push(output);
+            }
+        }
+        writer.outputs.extend(extra);
+
+        if let Some(path) = &args.bitrate_log {
+            writer.bitrate_log = Some(BitrateLog::new(path)?);
+        }
+
+        writer.ads = File::new_ads(&args.file, channel)?;
+        writer.container = args.container;
+        writer.no_header = args.no_header;
 
         Ok(writer)
     }
 
-    fn add_output(&mut self, output: Option<impl Output + 'static>) {
+    pub const fn begin_segment(
+        &mut self,
+        sequence: usize,
+        duration: Option<Duration>,
+        is_ad: bool,
+    ) {
+        self.stats = SegmentStats {
+            sequence,
+            duration,
+            bytes: 0,
+            total: self.stats.total,
+        };
+        self.is_ad = is_ad;
+    }
+
+    //Returns false without a recording to hook into
+    pub fn on_recorded(&self, hook: impl Fn(usize) + Send + Sync + 'static) -> bool {
+        self.on_recorded
+            .as_ref()
+            .is_some_and(|on_recorded| on_recorded.set(Box::new(hook)).is_ok())
+    }
+
+    pub const fn records_ads(&self) -> bool {
+        self.ads.is_some()
+    }
+
+    //Keeps a copy of the last content segment to repeat in place of ads
+    pub const fn enable_freeze(&mut self) {
+        self.freeze = true;
+    }
+
+    pub fn freeze(&mut self) -> io::Result<()> {
+        if self.freeze_last.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Repeating last segment in place of ad");
+        let last = mem::take(&mut self.freeze_last);
+        let result = self.handle_outputs(|output| output.freeze(&last));
+        self.freeze_last = last;
+
+        result
+    }
+
+    fn add_output<'a>(
+        outputs: &mut Vec<(Box<dyn Output>, &'a QueueArgs, &'static str)>,
+        output: Option<impl Output + 'static>,
+        queue: &'a QueueArgs,
+        name: &'static str,
+    ) {
         if let Some(output) = output {
-            self.outputs.push(Box::new(output));
+            outputs.push((Box::new(output), queue, name));
         }
     }
 
@@ -0,0 +1,73 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::info;
+
+use super::SegmentStats;
+
+pub struct BitrateLog {
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl Drop for BitrateLog {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+impl BitrateLog {
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+    pub fn new(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Failed to open bitrate log")?;
+
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if is_empty {
+            writeln!(writer, "timestamp,sequence,duration,bytes,bitrate")?;
+        }
+
+        info!("Logging segment bitrate to: {path}");
+        Ok(Self {
+            writer,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn log(&mut self, stats: &SegmentStats) -> io::Result<()> {
+        write!(
+            self.writer,
+            "{},{},",
+            chrono::Local::now().to_rfc3339(),
+            stats.sequence,
+        )?;
+
+        //Prefetch segments don't have a duration yet, leave those columns empty
+        match stats.duration.filter(|d| d.as_micros() > 0) {
+            Some(duration) => writeln!(
+                self.writer,
+                "{:.3},{},{}",
+                duration.as_secs_f64(),
+                stats.bytes,
+                u128::from(stats.bytes) * 8 * 1_000_000 / duration.as_micros(),
+            )?,
+            None => writeln!(self.writer, ",{},", stats.bytes)?,
+        }
+
+        if self.last_flush.elapsed() >= Self::FLUSH_INTERVAL {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+
+        Ok(())
+    }
+}
@@ -1,37 +1,213 @@
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, symlink};
+
 use std::{
-    fs,
+    fmt::{self, Display, Formatter},
+    fs::{self, DirBuilder, OpenOptions},
     io::{self, ErrorKind, Write},
+    mem,
     path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, ensure};
+use chrono::{DateTime, Local};
+use log::{debug, info, warn};
+
+use super::{Container, Output, SegmentStats, manifest::Manifest};
+use crate::{
+    args::{Parse, Parser},
+    constants,
 };
 
-use anyhow::Result;
-use log::{debug, info};
+//Called from the record thread with the media sequence of every segment written to disk
+pub type RecordedHook = Arc<OnceLock<Box<dyn Fn(usize) + Send + Sync>>>;
+
+#[derive(Debug)]
+pub struct RecordingFinishedError;
+
+impl std::error::Error for RecordingFinishedError {}
 
-use super::Output;
-use crate::args::{Parse, Parser};
+impl Display for RecordingFinishedError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Unhandled recording finished")
+    }
+}
 
-#[derive(Default, Debug)]
+#[derive(Clone, Debug)]
 pub struct Args {
     path: Option<String>,
+    ads_path: Option<String>,
     overwrite: bool,
+    concat_list: Option<String>,
+    manifest: Option<String>,
+    total: Option<Duration>,
+    mode: Option<u32>,
+    dir_mode: Option<u32>,
+    clock: Clock,
+    latest_link: Option<String>,
+    trim_last_partial: bool,
+    index_width: usize,
+    index_start: u64,
+    daily_dir: Option<String>,
+    ring: Option<u64>,
+    pub container: Option<Container>, //set by the general --container option
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            path: Option::default(),
+            ads_path: Option::default(),
+            overwrite: bool::default(),
+            concat_list: Option::default(),
+            manifest: Option::default(),
+            total: Option::default(),
+            mode: Option::default(),
+            dir_mode: Option::default(),
+            clock: Clock::default(),
+            latest_link: Option::default(),
+            trim_last_partial: bool::default(),
+            index_width: 5,
+            index_start: u64::default(),
+            daily_dir: Option::default(),
+            ring: Option::default(),
+            container: Option::default(),
+        }
+    }
 }
 
 impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
         parser.parse_opt_cfg(&mut self.path, "-r", "record")?;
         parser.parse_switch(&mut self.overwrite, "--overwrite")?;
+        parser.parse_opt(&mut self.ads_path, "--record-ads")?;
+        parser.parse_opt(&mut self.concat_list, "--record-concat-list")?;
+        parser.parse_opt(&mut self.manifest, "--record-manifest")?;
+        parser.parse_fn(&mut self.total, "--record-total", |arg| {
+            Ok(Some(Duration::try_from_secs_f64(arg.parse()?)?))
+        })?;
+        parser.parse_fn(&mut self.mode, "--record-mode", Self::parse_mode)?;
+        parser.parse_fn(&mut self.dir_mode, "--record-dir-mode", Self::parse_mode)?;
+        parser.parse_fn(&mut self.clock, "--record-timestamp", Clock::fixed)?;
+        parser.parse_opt(&mut self.latest_link, "--record-latest-link")?;
+        parser.parse_switch(&mut self.trim_last_partial, "--trim-last-partial")?;
+        parser.parse(&mut self.index_width, "--record-index-width")?;
+        ensure!(
+            (1..=20).contains(&self.index_width),
+            "--record-index-width must be between 1 and 20"
+        );
+        parser.parse(&mut self.index_start, "--record-index-start")?;
+
+        //-r is only used for the filename, so it can be omitted
+        parser.parse_opt(&mut self.daily_dir, "--record-daily-dir")?;
+        if self.daily_dir.is_some() {
+            let path = self.path.get_or_insert_with(|| "recording".to_owned());
+            ensure!(
+                !path.contains(std::path::is_separator),
+                "-r cannot contain a directory when used with --record-daily-dir"
+            );
+        }
+
+        parser.parse_opt(&mut self.ring, "--record-ring")?;
+        if let Some(slots) = self.ring {
+            ensure!(slots > 0, "--record-ring must be greater than 0");
+            ensure!(
+                self.concat_list.is_none() && self.manifest.is_none() && self.daily_dir.is_none(),
+                "--record-ring cannot be used with --record-concat-list, --record-manifest or --record-daily-dir"
+            );
+        }
 
         Ok(())
     }
 }
 
+impl Args {
+    pub const fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    //Files that would be written by every recording at once
+    pub const fn has_shared_files(&self) -> bool {
+        self.concat_list.is_some() || self.manifest.is_some() || self.latest_link.is_some()
+    }
+
+    fn parse_mode(arg: &str) -> Result<Option<u32>> {
+        let mode = u32::from_str_radix(arg, 8).context("Invalid octal file mode")?;
+        ensure!(mode <= 0o7777, "Invalid octal file mode");
+
+        Ok(Some(mode))
+    }
+}
+
+//Source of the timestamp in recording filenames
+#[derive(Default, Clone, Debug)]
+enum Clock {
+    #[default]
+    Local,
+    Fixed(String),
+}
+
+impl Clock {
+    fn fixed(arg: &str) -> Result<Self> {
+        let timestamp = arg
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+
+        ensure!(!timestamp.is_empty(), "--record-timestamp cannot be empty");
+        Ok(Self::Fixed(timestamp))
+    }
+
+    fn timestamp(&self, now: &DateTime<Local>) -> String {
+        match self {
+            Self::Local => now.format("%Y-%m-%d_%H-%M-%S").to_string(),
+            Self::Fixed(timestamp) => timestamp.clone(),
+        }
+    }
+}
+
 pub struct File {
     base_path: PathBuf,
     channel: String,
     overwrite: bool,
     header: Option<Vec<u8>>,
-    current: Option<fs::File>,
+    current: Option<(fs::File, PathBuf)>,
     segment_index: u64,
+    index_width: usize,
+    started: bool, //first file was created
+    daily_dir: Option<PathBuf>,
+    ring: Option<Ring>,
+    concat_list: Option<ConcatList>,
+    manifest: Option<Manifest>,
+    total: Option<Duration>,
+    mode: Option<u32>,
+    dir_mode: Option<u32>,
+    recorded: Duration,
+    audio_only: bool,
+    quality: Option<String>,
+    clock: Clock,
+    latest_link: Option<PathBuf>,
+    container: Option<Container>,
+    trim_last_partial: bool,
+    last: Option<LastSegment>,
+    previous_path: Option<PathBuf>,
+    longest: Duration,
+    on_recorded: RecordedHook,
+}
+
+//Most recently recorded segment file, kept so it can be trimmed when the stream ends
+//and so the latest link can be restored
+struct LastSegment {
+    path: PathBuf,
+    duration: Option<Duration>,
 }
 
 impl Output for File {
@@ -39,6 +215,119 @@ impl Output for File {
         self.header = Some(header.to_vec());
         Ok(())
     }
+
+    //A switch starts a new file so the resolution change is on a file boundary,
+    //audio only parts of the recording are also kept apart by their extension
+    fn set_quality(&mut self, quality: &str) -> io::Result<()> {
+        if self.quality.as_deref() == Some(quality) {
+            return Ok(());
+        }
+
+        if self.quality.is_some() {
+            self.discontinuity()?;
+        }
+
+        self.audio_only = quality == constants::AUDIO_ONLY_QUALITY;
+        self.quality = Some(quality.to_owned());
+        Ok(())
+    }
+
+    //Start a new file so the gap is on a file boundary
+    fn discontinuity(&mut self) -> io::Result<()> {
+        self.flush()?;
+        if let Some(manifest) = &mut self.manifest {
+            manifest.discontinuity();
+        }
+
+        Ok(())
+    }
+
+    fn segment_stats(&mut self, stats: &SegmentStats) -> io::Result<()> {
+        self.recorded = stats.total;
+        if let Some(last) = &mut self.last {
+            last.duration = stats.duration;
+        }
+        if let Some(duration) = stats.duration {
+            self.longest = self.longest.max(duration);
+        }
+        if let Some(manifest) = &mut self.manifest {
+            manifest.commit(stats.duration)?;
+        }
+        if let Some(on_recorded) = self.on_recorded.get() {
+            on_recorded(stats.sequence);
+        }
+
+        if let Some(total) = self.total
+            && self.recorded >= total
+        {
+            return Err(io::Error::other(RecordingFinishedError));
+        }
+
+        Ok(())
+    }
+
+    //Twitch's target duration is much longer than its segments, so compare against those instead
+    fn stream_ended(&mut self) -> io::Result<()> {
+        if !self.trim_last_partial {
+            return Ok(());
+        }
+
+        let Some(LastSegment {
+            path,
+            duration: Some(duration),
+        }) = self.last.take()
+        else {
+            return Ok(());
+        };
+
+        if duration >= self.longest / 2 {
+            return Ok(());
+        }
+
+        info!(
+            "Removing partial final segment {} ({:.3}s)",
+            path.display(),
+            duration.as_secs_f64()
+        );
+        fs::remove_file(&path)?;
+        self.recorded = self.recorded.saturating_sub(duration);
+
+        if let Some(concat_list) = &mut self.concat_list {
+            concat_list.remove_last()?;
+        }
+
+        if let Some(manifest) = &mut self.manifest {
+            manifest.remove_last()?;
+        }
+
+        self.restore_latest_link(self.previous_path.as_deref());
+        Ok(())
+    }
+
+    //Never kept, so the recording only has complete segments
+    fn abort_segment(&mut self) -> io::Result<()> {
+        let Some((file, path)) = self.current.take() else {
+            return Ok(());
+        };
+
+        drop(file);
+        info!("Removing incomplete segment {}", path.display());
+        fs::remove_file(&path)?;
+
+        self.restore_latest_link(self.last.as_ref().map(|last| last.path.as_path()));
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if !self.recorded.is_zero() {
+            info!(
+                "Recorded {:.3}s of stream content",
+                self.recorded.as_secs_f64()
+            );
+        }
+    }
 }
 
 impl Write for File {
@@ -47,11 +336,26 @@ impl Write for File {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if let Some(file) = self.current.as_mut() {
+        if let Some((mut file, path)) = self.current.take() {
             file.flush()?;
+
+            if let Some(concat_list) = &mut self.concat_list {
+                concat_list.append(&path)?;
+            }
+
+            if let Some(manifest) = &mut self.manifest {
+                manifest.stage(&path, file.metadata()?.len(), self.header.as_deref())?;
+            }
+
+            self.previous_path = self
+                .last
+                .replace(LastSegment {
+                    path,
+                    duration: None,
+                })
+                .map(|last| last.path);
         }
 
-        self.current = None;
         Ok(())
     }
 
@@ -60,26 +364,122 @@ impl Write for File {
         self.current
             .as_mut()
             .expect("File handle missing after ensure_file")
+            .0
             .write_all(buf)
     }
 }
 
 impl File {
+    //Filename token, not a format string
+    #[allow(clippy::literal_string_with_formatting_args)]
+    const QUALITY_TOKEN: &str = "{quality}";
+
+    //Used for the token with forced playlist URLs, which have no quality name
+    const UNKNOWN_QUALITY: &str = "unknown";
+
     pub fn new(args: &Args, channel: &str) -> Result<Option<Self>> {
         let Some(path) = &args.path else {
             return Ok(None);
         };
 
-        info!("Recording segments to: {path}");
+        if cfg!(not(unix)) && (args.mode.is_some() || args.dir_mode.is_some()) {
+            debug!("File modes are unsupported on this platform, ignoring");
+        }
 
-        Ok(Some(Self {
-            base_path: PathBuf::from(path),
+        let mut file = Self {
+            base_path: Self::resolve_base_path(path),
             channel: channel.to_owned(),
             overwrite: args.overwrite,
             header: None,
             current: None,
-            segment_index: 0,
-        }))
+            segment_index: args.index_start,
+            index_width: args.index_width,
+            started: false,
+            daily_dir: args.daily_dir.as_ref().map(PathBuf::from),
+            ring: None,
+            concat_list: args
+                .concat_list
+                .as_ref()
+                .map(|p| ConcatList::new(p))
+                .transpose()?,
+            manifest: args
+                .manifest
+                .as_ref()
+                .map(|p| Manifest::new(p, !args.overwrite))
+                .transpose()?,
+            total: args.total,
+            mode: args.mode,
+            dir_mode: args.dir_mode,
+            recorded: Duration::ZERO,
+            audio_only: false,
+            quality: None,
+            clock: args.clock.clone(),
+            latest_link: args.latest_link.as_ref().map(PathBuf::from),
+            container: args.container,
+            trim_last_partial: args.trim_last_partial,
+            last: None,
+            previous_path: None,
+            longest: Duration::ZERO,
+            on_recorded: RecordedHook::default(),
+        };
+
+        //Named like the slots, which needs the ring to be set
+        if let Some(slots) = args.ring {
+            file.ring = Some(Ring {
+                slots,
+                index_path: PathBuf::default(),
+            });
+
+            let index_path = file
+                .build_path("", "newest", "", Self::UNKNOWN_QUALITY)
+                .with_extension("txt");
+            let ring = file.ring.as_mut().expect("Missing ring after setting it");
+            ring.index_path = index_path;
+
+            file.segment_index = ring.next_slot();
+        }
+
+        info!(
+            "Recording segments to: {}",
+            file.build_path("<TIMESTAMP>", "<INDEX>", "<DATE>", "<QUALITY>")
+                .display()
+        );
+        Ok(Some(file))
+    }
+
+    //Directories get files with the default stem and extension inside of them
+    fn resolve_base_path(path: &str) -> PathBuf {
+        let base_path = PathBuf::from(path);
+        if path.ends_with(std::path::is_separator) || base_path.is_dir() {
+            return base_path.join("recording");
+        }
+
+        base_path
+    }
+
+    //Ads are recorded separately and don't count towards the main recording
+    pub fn on_recorded(&self) -> RecordedHook {
+        self.on_recorded.clone()
+    }
+
+    pub fn new_ads(args: &Args, channel: &str) -> Result<Option<Self>> {
+        let Some(path) = &args.ads_path else {
+            return Ok(None);
+        };
+
+        Self::new(
+            &Args {
+                path: Some(path.clone()),
+                concat_list: None,
+                manifest: None,
+                total: None,
+                latest_link: None,
+                daily_dir: None,
+                ring: None,
+                ..args.clone()
+            },
+            channel,
+        )
     }
 
     fn ensure_file(&mut self) -> io::Result<()> {
@@ -91,51 +491,162 @@ impl File {
         Ok(())
     }
 
-    fn create_segment_file(&mut self) -> io::Result<fs::File> {
-        let timestamp = Self::timestamp();
+    fn create_segment_file(&mut self) -> io::Result<(fs::File, PathBuf)> {
+        //Same instant for the filename and the daily directory, so they never disagree at midnight
+        let now = Local::now();
+        let timestamp = self.clock.timestamp(&now);
+        let date = now.format("%Y-%m-%d").to_string();
         let mut attempt = 0;
 
         loop {
-            let index = self.segment_index + attempt;
-            let path = self.segment_path(&timestamp, index);
+            let index = self.segment_index.saturating_add(attempt);
+            let path = self.segment_path(&timestamp, index, &date);
+            self.create_parent_dir(&path)?;
 
-            let result = if self.overwrite {
-                fs::File::create(&path)
-            } else {
-                fs::File::create_new(&path)
-            };
-
-            match result {
+            match self.open_options().open(&path) {
                 Ok(mut file) => {
                     if let Some(header) = &self.header {
                         file.write_all(header)?;
                     }
 
-                    if self.segment_index == 0 && attempt == 0 {
-                        info!("Recording to: {}", path.display());
-                    } else {
+                    if mem::replace(&mut self.started, true) {
                         debug!("Recording to: {}", path.display());
+                    } else {
+                        info!("Recording to: {}", path.display());
                     }
 
-                    self.segment_index = index.saturating_add(1);
-                    return Ok(file);
+                    self.segment_index = match &self.ring {
+                        Some(ring) => {
+                            ring.update(index)?;
+                            (index + 1) % ring.slots
+                        }
+                        None => index.saturating_add(1),
+                    };
+                    if let Some(link) = &self.latest_link
+                        && let Err(e) = Self::update_latest_link(link, &path)
+                    {
+                        warn!("Failed to update {}: {e}", link.display());
+                    }
+
+                    return Ok((file, path));
                 }
                 Err(error) if !self.overwrite && error.kind() == ErrorKind::AlreadyExists => {
                     attempt = attempt.saturating_add(1);
-                    continue;
                 }
                 Err(error) => return Err(error),
             }
         }
     }
 
-    fn segment_path(&self, timestamp: &str, index: u64) -> PathBuf {
-        let (stem, ext) = Self::split_stem_ext(&self.base_path);
-        let mut filename = format!("{stem}_{}_{}_{index:05}", self.channel, timestamp);
-        filename.push('.');
-        filename.push_str(&ext);
+    //Points the link back at an earlier segment after the newest one was removed
+    fn restore_latest_link(&self, target: Option<&Path>) {
+        let Some(link) = &self.latest_link else {
+            return;
+        };
+
+        let result = target.map_or_else(
+            || fs::remove_file(link),
+            |target| Self::update_latest_link(link, target),
+        );
+
+        if let Err(e) = result {
+            warn!("Failed to update {}: {e}", link.display());
+        }
+    }
+
+    //Replaced atomically so readers never see a missing or partial link
+    fn update_latest_link(link: &Path, target: &Path) -> io::Result<()> {
+        let mut temp = link.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        let target = target.canonicalize()?;
+        match fs::remove_file(&temp) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+            _ => (),
+        }
+
+        #[cfg(unix)]
+        symlink(&target, &temp)?;
+
+        //Without symlinks, write the path of the newest file instead
+        #[cfg(not(unix))]
+        fs::write(&temp, format!("{}\n", target.display()))?;
+
+        fs::rename(&temp, link)
+    }
+
+    fn open_options(&self) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.write(true);
+
+        //Ring slots are always reused
+        if self.overwrite || self.ring.is_some() {
+            options.create(true).truncate(true);
+        } else {
+            options.create_new(true);
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            options.mode(mode);
+        }
+
+        options
+    }
+
+    fn create_parent_dir(&self, path: &Path) -> io::Result<()> {
+        let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) else {
+            return Ok(());
+        };
+
+        if parent.is_dir() {
+            return Ok(());
+        }
+
+        debug!("Creating directory: {}", parent.display());
 
-        if let Some(parent) = self
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+
+        #[cfg(unix)]
+        if let Some(mode) = self.dir_mode {
+            builder.mode(mode);
+        }
+
+        builder.create(parent)
+    }
+
+    fn segment_path(&self, timestamp: &str, index: u64, date: &str) -> PathBuf {
+        self.build_path(
+            timestamp,
+            &format!("{index:0width$}", width = self.index_width),
+            date,
+            self.quality.as_deref().unwrap_or(Self::UNKNOWN_QUALITY),
+        )
+    }
+
+    fn build_path(&self, timestamp: &str, index: &str, date: &str, quality: &str) -> PathBuf {
+        let (stem, ext) = Self::split_stem_ext(&self.base_path, self.container);
+        let stem = stem.replace(Self::QUALITY_TOKEN, quality);
+        //Ring slots keep their names, so they can't contain a timestamp
+        let mut filename = if self.ring.is_some() {
+            format!("{stem}_{}_{index}", self.channel)
+        } else {
+            format!("{stem}_{}_{timestamp}_{index}", self.channel)
+        };
+        if self.audio_only {
+            filename.push_str(".audio");
+        }
+        if !ext.is_empty() {
+            filename.push('.');
+            filename.push_str(&ext);
+        }
+
+        //Checked for every new file, so the directory rolls over at local midnight
+        if let Some(daily_dir) = &self.daily_dir {
+            daily_dir.join(date).join(filename)
+        } else if let Some(parent) = self
             .base_path
             .parent()
             .filter(|p| !p.as_os_str().is_empty())
@@ -146,7 +657,8 @@ impl File {
         }
     }
 
-    fn split_stem_ext(path: &Path) -> (String, String) {
+    //--container takes priority over the recording path's extension
+    fn split_stem_ext(path: &Path, container: Option<Container>) -> (String, String) {
         let stem = path
             .file_stem()
             .or_else(|| path.file_name())
@@ -154,16 +666,91 @@ impl File {
             .filter(|s| !s.is_empty())
             .unwrap_or_else(|| "recording".to_owned());
 
-        let ext = path
+        let path_ext = path
             .extension()
             .map(|e| e.to_string_lossy().into_owned())
-            .filter(|e| !e.is_empty())
-            .unwrap_or_else(|| "ts".to_owned());
+            .filter(|e| !e.is_empty());
+
+        let ext = match container {
+            Some(Container::Ts) => "ts".to_owned(),
+            Some(Container::Mp4) => "mp4".to_owned(),
+            Some(Container::Raw) => path_ext.unwrap_or_default(),
+            None => path_ext.unwrap_or_else(|| "ts".to_owned()),
+        };
 
         (stem, ext)
     }
+}
+
+//Fixed set of files reused in order, the index file holds the newest slot so restarts continue after it
+struct Ring {
+    slots: u64,
+    index_path: PathBuf,
+}
+
+impl Ring {
+    fn next_slot(&self) -> u64 {
+        fs::read_to_string(&self.index_path)
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .filter(|slot| *slot < self.slots)
+            .map_or(0, |slot| (slot + 1) % self.slots)
+    }
+
+    //Replaced atomically so it's never empty after a crash
+    fn update(&self, slot: u64) -> io::Result<()> {
+        let mut temp = self.index_path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        fs::write(&temp, format!("{slot}\n"))?;
+        fs::rename(&temp, &self.index_path)
+    }
+}
+
+//ffmpeg concat demuxer list, see https://ffmpeg.org/ffmpeg-formats.html#concat
+struct ConcatList {
+    file: fs::File,
+    dir: PathBuf,
+    last_len: u64, //length before the last entry
+}
 
-    fn timestamp() -> String {
-        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string()
+impl ConcatList {
+    fn new(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Failed to open concat list")?;
+
+        let dir = Path::new(path)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+            .canonicalize()
+            .context("Failed to resolve concat list directory")?;
+
+        info!("Writing concat list to: {path}");
+        Ok(Self {
+            last_len: file.metadata().context("Failed to read concat list")?.len(),
+            file,
+            dir,
+        })
+    }
+
+    fn append(&mut self, path: &Path) -> io::Result<()> {
+        //Relative paths are resolved from the list's directory by ffmpeg,
+        //absolute paths require -safe 0
+        let path = path.canonicalize()?;
+        let path = path.strip_prefix(&self.dir).unwrap_or(&path);
+
+        self.last_len = self.file.metadata()?.len();
+        writeln!(
+            self.file,
+            "file '{}'",
+            path.to_string_lossy().replace('\'', r"'\''")
+        )
+    }
+    fn remove_last(&self) -> io::Result<()> {
+        self.file.set_len(self.last_len)
     }
 }
@@ -0,0 +1,227 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+//HLS media playlist referencing every recorded file, so the recording can be served as a VOD.
+//Rewritten for every segment because the target duration has to cover the longest one.
+pub struct Manifest {
+    path: PathBuf,
+    dir: PathBuf,
+    previous: String, //entries from an earlier run that are kept
+    entries: Vec<String>,
+    target_duration: u64,
+    last_duration: Duration,
+    pending: Option<Pending>,
+    header: Option<Vec<u8>>, //last header referenced by #EXT-X-MAP
+    discontinuity: bool,
+}
+
+//Closed file waiting for its duration
+struct Pending {
+    uri: String,
+    size: u64,
+    header_len: u64,
+    map: bool,
+}
+
+impl Drop for Manifest {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit(None).and_then(|()| self.write(true)) {
+            warn!("Failed to finalize manifest {}: {e}", self.path.display());
+        }
+    }
+}
+
+impl Manifest {
+    //Written again by every run
+    const HEADER_TAGS: [&str; 5] = [
+        "#EXTM3U",
+        "#EXT-X-VERSION",
+        "#EXT-X-MEDIA-SEQUENCE",
+        "#EXT-X-PLAYLIST-TYPE",
+        "#EXT-X-ENDLIST",
+    ];
+
+    pub fn new(path: &str, resume: bool) -> Result<Self> {
+        let dir = Path::new(path)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+            .canonicalize()
+            .context("Failed to resolve manifest directory")?;
+
+        let mut manifest = Self {
+            path: PathBuf::from(path),
+            dir,
+            previous: String::default(),
+            entries: Vec::default(),
+            target_duration: u64::default(),
+            last_duration: Duration::ZERO,
+            pending: None,
+            header: None,
+            discontinuity: false,
+        };
+
+        if resume {
+            manifest.resume()?;
+        }
+
+        manifest.write(false).context("Failed to write manifest")?;
+        info!("Writing manifest to: {path}");
+
+        Ok(manifest)
+    }
+
+    pub const fn discontinuity(&mut self) {
+        self.discontinuity = true;
+    }
+
+    //Files start with the header, so fMP4 entries map it by byte range instead of copying it
+    pub fn stage(&mut self, path: &Path, size: u64, header: Option<&[u8]>) -> io::Result<()> {
+        self.commit(None)?;
+
+        let map = header.is_some() && self.header.as_deref() != header;
+        if map {
+            self.header = header.map(<[u8]>::to_vec);
+        }
+
+        self.pending = Some(Pending {
+            uri: self.relative_uri(path)?,
+            size,
+            header_len: header.map_or(0, |h| h.len() as u64),
+            map,
+        });
+
+        Ok(())
+    }
+
+    //Prefetch segments don't have a duration, assume it's the same as the last one
+    pub fn commit(&mut self, duration: Option<Duration>) -> io::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+
+        let duration = duration.unwrap_or(self.last_duration);
+        self.last_duration = duration;
+        self.target_duration = self
+            .target_duration
+            .max((duration + Duration::from_millis(500)).as_secs());
+
+        let mut entry = String::new();
+        if self.discontinuity {
+            self.discontinuity = false;
+            entry.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        if pending.map {
+            _ = writeln!(
+                entry,
+                "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@0\"",
+                pending.uri, pending.header_len
+            );
+        }
+
+        _ = writeln!(entry, "#EXTINF:{:.3},", duration.as_secs_f64());
+        if pending.header_len > 0 {
+            _ = writeln!(
+                entry,
+                "#EXT-X-BYTERANGE:{}@{}",
+                pending.size.saturating_sub(pending.header_len),
+                pending.header_len
+            );
+        }
+        _ = writeln!(entry, "{}", pending.uri);
+
+        self.entries.push(entry);
+        self.write(false)
+    }
+
+    pub fn remove_last(&mut self) -> io::Result<()> {
+        if self.pending.take().is_none() {
+            self.entries.pop();
+        }
+
+        self.write(false)
+    }
+
+    //Replaced atomically so players never read a partial playlist
+    fn write(&self, ended: bool) -> io::Result<()> {
+        let mut playlist = format!(
+            "#EXTM3U\n\
+             #EXT-X-VERSION:6\n\
+             #EXT-X-TARGETDURATION:{}\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:EVENT\n",
+            self.target_duration
+        );
+        playlist.push_str(&self.previous);
+        for entry in &self.entries {
+            playlist.push_str(entry);
+        }
+        if ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        fs::write(&temp, playlist)?;
+        fs::rename(&temp, &self.path)
+    }
+
+    //Keeps the entries of an existing manifest, with a discontinuity before the new ones
+    fn resume(&mut self) -> Result<()> {
+        let playlist = match fs::read_to_string(&self.path) {
+            Ok(playlist) => playlist,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context("Failed to read manifest"),
+        };
+
+        for line in playlist.lines().filter(|l| !l.is_empty()) {
+            if let Some(target) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+                self.target_duration = target.parse().unwrap_or_default();
+            } else if !Self::HEADER_TAGS.iter().any(|t| line.starts_with(t)) {
+                self.previous.push_str(line);
+                self.previous.push('\n');
+            }
+        }
+
+        self.discontinuity = !self.previous.is_empty();
+        Ok(())
+    }
+
+    //Relative to the manifest's directory, with forward slashes as in any other URI
+    fn relative_uri(&self, path: &Path) -> io::Result<String> {
+        let path = path.canonicalize()?;
+        let common = self
+            .dir
+            .components()
+            .zip(path.components())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        //Different root or drive, can only be referenced by absolute path
+        if common == 0 {
+            return Ok(path.to_string_lossy().into_owned());
+        }
+
+        let parents = self.dir.components().count() - common;
+        let uri = (0..parents)
+            .map(|_| "..".into())
+            .chain(
+                path.components()
+                    .skip(common)
+                    .map(|c| c.as_os_str().to_string_lossy()),
+            )
+            .collect::<Vec<_>>()
+            .join("/");
+
+        Ok(uri)
+    }
+}
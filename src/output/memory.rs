@@ -0,0 +1,129 @@
+//Unit tests only use part of it without the feature
+#![cfg_attr(not(feature = "testing"), allow(dead_code))]
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use super::{Output, SegmentStats};
+
+/// A call made to a [`Memory`] output, in the order it was made.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Header(Vec<u8>),
+    Write(Vec<u8>),
+    Flush,
+    Abort,
+    Freeze(Vec<u8>),
+    Stats(SegmentStats),
+    Quality(String),
+    Discontinuity,
+    StreamEnded,
+}
+
+/// [`Output`] that records every call in memory instead of writing anywhere,
+/// for testing a [`Session`](crate::Session) against a mock server.
+///
+/// Clones share the same events, so keep one to inspect them after passing
+/// another to [`Session::run`](crate::Session::run).
+#[derive(Clone, Default, Debug)]
+pub struct Memory {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Output for Memory {
+    fn set_header(&mut self, header: &[u8]) -> io::Result<()> {
+        self.push(Event::Header(header.to_vec()));
+        Ok(())
+    }
+
+    fn segment_stats(&mut self, stats: &SegmentStats) -> io::Result<()> {
+        self.push(Event::Stats(*stats));
+        Ok(())
+    }
+
+    fn freeze(&mut self, segment: &[u8]) -> io::Result<()> {
+        self.push(Event::Freeze(segment.to_vec()));
+        Ok(())
+    }
+
+    fn abort_segment(&mut self) -> io::Result<()> {
+        self.push(Event::Abort);
+        Ok(())
+    }
+
+    fn set_quality(&mut self, quality: &str) -> io::Result<()> {
+        self.push(Event::Quality(quality.to_owned()));
+        Ok(())
+    }
+
+    fn discontinuity(&mut self) -> io::Result<()> {
+        self.push(Event::Discontinuity);
+        Ok(())
+    }
+
+    fn stream_ended(&mut self) -> io::Result<()> {
+        self.push(Event::StreamEnded);
+        Ok(())
+    }
+}
+
+impl Write for Memory {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        unreachable!();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.push(Event::Flush);
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.push(Event::Write(buf.to_vec()));
+        Ok(())
+    }
+}
+
+impl Memory {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every call made so far.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread panicked while recording an event.
+    #[must_use]
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().expect("Memory lock poisoned").clone()
+    }
+
+    /// Data of every completed segment, joined from the writes before each flush.
+    ///
+    /// Writes of aborted segments are left out.
+    #[must_use]
+    pub fn segments(&self) -> Vec<Vec<u8>> {
+        let mut segments = Vec::new();
+        let mut current = Vec::new();
+        for event in self.events() {
+            match event {
+                Event::Write(data) => current.extend_from_slice(&data),
+                Event::Flush => segments.push(std::mem::take(&mut current)),
+                Event::Abort => current.clear(),
+                _ => (),
+            }
+        }
+
+        segments
+    }
+
+    fn push(&self, event: Event) {
+        self.events
+            .lock()
+            .expect("Memory lock poisoned")
+            .push(event);
+    }
+}
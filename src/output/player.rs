@@ -1,14 +1,19 @@
 use std::{
     borrow::Cow,
+    env,
     fmt::{self, Display, Formatter},
+    fs::OpenOptions,
     io::{self, ErrorKind::BrokenPipe, Write},
+    path::Path,
     process::{Child, ChildStdin, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, bail, ensure};
 use log::{error, info};
 
-use super::Output;
+use super::{Output, SegmentStats};
 use crate::args::{Parse, Parser};
 
 #[derive(Debug)]
@@ -22,12 +27,54 @@ impl Display for PlayerClosedError {
     }
 }
 
+//Where the player's stdout and stderr go
+#[derive(Default, Clone, Debug)]
+enum PlayerOutput {
+    #[default]
+    Inherit,
+    Null,
+    File(String),
+}
+
+impl PlayerOutput {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "inherit" => Ok(Self::Inherit),
+            "null" => Ok(Self::Null),
+            "" => bail!("Invalid player output, must be inherit, null or a file path"),
+            path => Ok(Self::File(path.to_owned())),
+        }
+    }
+
+    fn apply(&self, command: &mut Command) -> Result<()> {
+        match self {
+            Self::Inherit => (),
+            Self::Null => {
+                command.stdout(Stdio::null()).stderr(Stdio::null());
+            }
+            Self::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context("Failed to open player output file")?;
+
+                command.stdout(file.try_clone()?).stderr(file);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Args {
     path: Option<String>,
     pargs: Cow<'static, str>,
-    quiet: bool,
+    output: PlayerOutput,
+    pub quiet: bool, //set by the general --quiet option
     no_kill: bool,
+    pace: bool,
 }
 
 impl Default for Args {
@@ -35,27 +82,78 @@ impl Default for Args {
         Self {
             pargs: "-".into(),
             path: Option::default(),
+            output: PlayerOutput::default(),
             quiet: bool::default(),
             no_kill: bool::default(),
+            pace: bool::default(),
         }
     }
 }
 
 impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
-        parser.parse_opt_cfg(&mut self.path, "-p", "player")?;
-        parser.parse_cow_string_cfg(&mut self.pargs, "-a", "player-args")?;
-        parser.parse_switch_or(&mut self.quiet, "-q", "--quiet")?;
+        parser.parse_opt_or(&mut self.path, "-p", "--player")?;
+        if let Some(path) = &self.path {
+            ensure!(Self::is_executable(path), "Player not found: {path}");
+        }
+
+        parser.parse_cow_string_or(&mut self.pargs, "-a", "--player-args")?;
+        parser.parse_fn(&mut self.output, "--player-output", PlayerOutput::new)?;
         parser.parse_switch(&mut self.no_kill, "--no-kill")?;
+        parser.parse_switch(&mut self.pace, "--player-pace")?;
 
         Ok(())
     }
 }
 
+impl Args {
+    pub const fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub const fn is_paced(&self) -> bool {
+        self.pace
+    }
+
+    //Player argument tokens, not format strings
+    #[allow(clippy::literal_string_with_formatting_args)]
+    const URL_TOKEN: &str = "{url}";
+    #[allow(clippy::literal_string_with_formatting_args)]
+    const TITLE_TOKEN: &str = "{title}";
+
+    //{url} is what the player should open and {title} names the stream.
+    //Substituted after splitting, so they're always a single argument.
+    fn command_args(&self, url: &str, title: &str) -> Vec<String> {
+        self.pargs
+            .split_whitespace()
+            .map(|a| {
+                a.replace(Self::URL_TOKEN, url)
+                    .replace(Self::TITLE_TOKEN, title)
+            })
+            .collect()
+    }
+
+    //Paths with a directory are checked as is, bare names are looked up in PATH like Command does
+    fn is_executable(path: &str) -> bool {
+        let path = Path::new(path);
+        if path.components().count() > 1 {
+            return path.is_file();
+        }
+
+        env::var_os("PATH").is_some_and(|paths| {
+            env::split_paths(&paths).any(|dir| {
+                let candidate = dir.join(path);
+                candidate.is_file() || (cfg!(windows) && candidate.with_extension("exe").is_file())
+            })
+        })
+    }
+}
+
 pub struct Player {
     stdin: ChildStdin,
     process: Child,
     no_kill: bool,
+    pace: Option<Pace>,
 }
 
 impl Drop for Player {
@@ -74,6 +172,32 @@ impl Output for Player {
             .write_all(header)
             .map_err(|e| self.handle_broken_pipe(e))
     }
+
+    fn segment_stats(&mut self, stats: &SegmentStats) -> io::Result<()> {
+        if let Some(pace) = &mut self.pace {
+            pace.wait(stats.duration);
+        }
+
+        Ok(())
+    }
+
+    //Same timing as a real segment
+    fn freeze(&mut self, segment: &[u8]) -> io::Result<()> {
+        self.write_all(segment)?;
+        if let Some(pace) = &mut self.pace {
+            pace.wait(None);
+        }
+
+        Ok(())
+    }
+
+    fn discontinuity(&mut self) -> io::Result<()> {
+        if let Some(pace) = &mut self.pace {
+            pace.next = None;
+        }
+
+        Ok(())
+    }
 }
 
 impl Write for Player {
@@ -93,19 +217,24 @@ impl Write for Player {
 }
 
 impl Player {
-    pub fn new(args: &Args) -> Result<Option<Self>> {
+    //Segments are written to the player's stdin
+    pub fn new(args: &Args, title: &str) -> Result<Option<Self>> {
+        Self::spawn(args, &args.command_args("-", title))
+    }
+
+    fn spawn(args: &Args, pargs: &[String]) -> Result<Option<Self>> {
         let Some(path) = &args.path else {
             return Ok(None);
         };
 
-        info!("Opening player: {path} {}", args.pargs);
+        info!("Opening player: {path} {}", pargs.join(" "));
         let mut command = Command::new(path);
-        command
-            .args(args.pargs.split_whitespace())
-            .stdin(Stdio::piped());
+        command.args(pargs).stdin(Stdio::piped());
 
-        if args.quiet {
+        if args.quiet && matches!(args.output, PlayerOutput::Inherit) {
             command.stdout(Stdio::null()).stderr(Stdio::null());
+        } else {
+            args.output.apply(&mut command)?;
         }
 
         let mut process = command.spawn().context("Failed to open player")?;
@@ -118,30 +247,23 @@ impl Player {
             stdin,
             process,
             no_kill: args.no_kill,
+            pace: args.pace.then(Pace::default),
         }))
     }
 
-    pub fn passthrough(args: &mut Args, url: &str) -> Result<()> {
+    //Without {url}, the playlist URL replaces - or is appended
+    pub fn passthrough(args: &Args, url: &str, title: &str) -> Result<()> {
         info!("Passing through playlist URL to player");
-        if args.pargs.split_whitespace().any(|a| a == "-") {
-            args.pargs = args
-                .pargs
-                .split_whitespace()
-                .map(|a| {
-                    if a == "-" {
-                        url.to_owned()
-                    } else {
-                        a.to_owned()
-                    }
-                })
-                .collect::<Vec<String>>()
-                .join(" ")
-                .into();
-        } else {
-            args.pargs = format!("{} {url}", args.pargs).into();
+        let mut pargs = args.command_args(url, title);
+        if !args.pargs.contains(Args::URL_TOKEN) {
+            if let Some(arg) = pargs.iter_mut().find(|a| *a == "-") {
+                url.clone_into(arg);
+            } else {
+                pargs.push(url.to_owned());
+            }
         }
 
-        let Some(mut player) = Self::new(args)? else {
+        let Some(mut player) = Self::spawn(args, &pargs)? else {
             bail!("No player set");
         };
 
@@ -162,3 +284,26 @@ impl Player {
         error
     }
 }
+
+//Spaces segments by their duration so the player is fed in real time instead of in bursts
+#[derive(Default)]
+struct Pace {
+    next: Option<Instant>,
+    last_duration: Duration,
+}
+
+impl Pace {
+    //Prefetch segments don't have a duration yet, assume it's the same as the last one
+    fn wait(&mut self, duration: Option<Duration>) {
+        if let Some(duration) = duration {
+            self.last_duration = duration;
+        }
+
+        //Segments that arrive late are written right away and the schedule starts over
+        let now = Instant::now();
+        let next = self.next.map_or(now, |next| next.max(now)) + self.last_duration;
+        self.next = Some(next);
+
+        thread::sleep(next - now);
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    mem,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{Builder as ThreadBuilder, JoinHandle},
+};
+
+use anyhow::{Result, bail, ensure};
+use log::{debug, error};
+
+use super::{Output, SegmentStats};
+use crate::{args::Parser, metrics};
+
+#[derive(Default, Copy, Clone, Debug)]
+pub enum Policy {
+    #[default]
+    Block,
+    DropOldest,
+}
+
+impl Policy {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            _ => bail!("Invalid queue policy"),
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct Args {
+    depth: Option<usize>,
+    policy: Policy,
+    always: bool,
+}
+
+impl Args {
+    pub fn parse(
+        &mut self,
+        parser: &mut Parser,
+        depth_key: &'static str,
+        policy_key: &'static str,
+    ) -> Result<()> {
+        parser.parse_opt(&mut self.depth, depth_key)?;
+        parser.parse_fn(&mut self.policy, policy_key, Policy::new)?;
+        ensure!(
+            self.depth.is_none_or(|d| d > 0),
+            "{depth_key} must be greater than 0"
+        );
+
+        Ok(())
+    }
+
+    pub const fn is_enabled(&self) -> bool {
+        self.depth.is_some()
+    }
+
+    //Queued even when it's the only output, with `depth` if none was given
+    pub fn always(&self, depth: usize) -> Self {
+        Self {
+            depth: Some(self.depth.unwrap_or(depth)),
+            policy: self.policy,
+            always: true,
+        }
+    }
+
+    pub const fn is_always(&self) -> bool {
+        self.always
+    }
+}
+
+enum Message {
+    Segment(Vec<u8>, SegmentStats),
+    Header(Vec<u8>),
+    Quality(String),
+    Freeze(Vec<u8>),
+    Discontinuity,
+    StreamEnded,
+}
+
+impl Message {
+    //Repeated segments are as large as the real ones
+    const fn is_segment(&self) -> bool {
+        matches!(self, Self::Segment(..) | Self::Freeze(_))
+    }
+}
+
+//Output that is written from its own thread, so a slow output doesn't stall the others
+pub struct Queued {
+    queue: Arc<Queue>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+    policy: Policy,
+    buf: Vec<u8>,
+    freeze: bool, //only the player repeats segments, don't copy them for the others
+}
+
+impl Output for Queued {
+    fn set_header(&mut self, header: &[u8]) -> io::Result<()> {
+        self.send(Message::Header(header.to_vec()))
+    }
+
+    fn set_quality(&mut self, quality: &str) -> io::Result<()> {
+        self.send(Message::Quality(quality.to_owned()))
+    }
+
+    fn freeze(&mut self, segment: &[u8]) -> io::Result<()> {
+        if !self.freeze {
+            return Ok(());
+        }
+
+        self.send(Message::Freeze(segment.to_vec()))
+    }
+
+    //Nothing was sent yet, segments are only queued once complete
+    fn abort_segment(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        Ok(())
+    }
+
+    //Anything buffered belongs to a segment that won't be completed
+    fn discontinuity(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.send(Message::Discontinuity)
+    }
+
+    fn stream_ended(&mut self) -> io::Result<()> {
+        self.send(Message::StreamEnded)
+    }
+
+    //Segments are queued as a whole so dropping one never leaves a partial segment
+    fn segment_stats(&mut self, stats: &SegmentStats) -> io::Result<()> {
+        let capacity = self.buf.capacity();
+        let data = mem::replace(&mut self.buf, Vec::with_capacity(capacity));
+        self.send(Message::Segment(data, *stats))
+    }
+}
+
+impl Write for Queued {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        unreachable!();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.queue.close();
+        //Errors that weren't returned by a send are only reported here
+        match self.handle.take().map(JoinHandle::join) {
+            Some(Ok(Err(e))) => error!("Failed to write to {} output: {e}", self.queue.name),
+            Some(Err(_)) => error!("Output thread of {} panicked", self.queue.name),
+            Some(Ok(Ok(()))) | None => (),
+        }
+    }
+}
+
+impl Queued {
+    pub fn spawn(mut output: Box<dyn Output>, args: &Args, name: &'static str) -> io::Result<Self> {
+        let queue = Arc::new(Queue::new(args.depth.expect("Missing queue depth"), name));
+
+        let thread_queue = queue.clone();
+        let handle = ThreadBuilder::new()
+            .name(format!("{name} queue"))
+            .spawn(move || {
+                let result = Self::run(&mut *output, &thread_queue);
+                thread_queue.close();
+
+                result
+            })
+            .map_err(|e| io::Error::other(format!("Failed to spawn output thread: {e}")))?;
+
+        Ok(Self {
+            queue,
+            handle: Some(handle),
+            policy: args.policy,
+            buf: Vec::default(),
+            freeze: name == "player",
+        })
+    }
+
+    fn run(output: &mut dyn Output, queue: &Queue) -> io::Result<()> {
+        while let Some(message) = queue.pop() {
+            match message {
+                Message::Segment(data, stats) => {
+                    output.write_all(&data)?;
+                    output.flush()?;
+                    output.segment_stats(&stats)?;
+                }
+                Message::Header(header) => output.set_header(&header)?,
+                Message::Quality(quality) => output.set_quality(&quality)?,
+                Message::Freeze(segment) => output.freeze(&segment)?,
+                Message::Discontinuity => output.discontinuity()?,
+                Message::StreamEnded => output.stream_ended()?,
+            }
+        }
+
+        Ok(())
+    }
+
+    //Errors are reported by the thread when it exits
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        if self.queue.push(message, self.policy) {
+            return Ok(());
+        }
+
+        match self.handle.take().map(JoinHandle::join) {
+            Some(Ok(Err(error))) => Err(error),
+            Some(Err(_)) => Err(io::Error::other("Output thread panicked")),
+            Some(Ok(Ok(()))) | None => Err(io::Error::other("Output queue closed")),
+        }
+    }
+}
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    closed: bool,
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    depth: usize,
+    name: &'static str,
+}
+
+impl Queue {
+    fn new(depth: usize, name: &'static str) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::with_capacity(depth),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            depth,
+            name,
+        }
+    }
+
+    //Returns false if the queue was closed by the output thread
+    fn push(&self, message: Message, policy: Policy) -> bool {
+        let mut state = self.lock();
+        let is_segment = message.is_segment();
+
+        if is_segment {
+            while !state.closed && Self::segments(&state) >= self.depth {
+                match policy {
+                    Policy::Block => {
+                        debug!("{} queue full, waiting for output...", self.name);
+                        state = self
+                            .not_full
+                            .wait(state)
+                            .expect("Output queue lock poisoned");
+                    }
+                    Policy::DropOldest => {
+                        debug!("{} queue full, dropping oldest segment", self.name);
+
+                        let oldest = state
+                            .messages
+                            .iter()
+                            .position(Message::is_segment)
+                            .expect("Missing segment in full output queue");
+                        state.messages.remove(oldest);
+                        metrics::pop_queued(self.name);
+                    }
+                }
+            }
+        }
+
+        if state.closed {
+            return false;
+        }
+
+        state.messages.push_back(message);
+        drop(state);
+
+        if is_segment {
+            metrics::push_queued(self.name);
+        }
+
+        self.not_empty.notify_one();
+
+        true
+    }
+
+    fn pop(&self) -> Option<Message> {
+        let mut state = self.lock();
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                self.not_full.notify_one();
+                if message.is_segment() {
+                    metrics::pop_queued(self.name);
+                }
+
+                return Some(message);
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            state = self
+                .not_empty
+                .wait(state)
+                .expect("Output queue lock poisoned");
+        }
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().expect("Output queue lock poisoned")
+    }
+
+    //Only segments count towards the depth, other messages are small and can't be dropped
+    fn segments(state: &QueueState) -> usize {
+        state.messages.iter().filter(|m| m.is_segment()).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{Event, Memory};
+
+    fn queued(memory: &Memory) -> io::Result<Queued> {
+        let args = Args {
+            depth: Some(4),
+            ..Args::default()
+        };
+
+        Queued::spawn(Box::new(memory.clone()), &args, "test")
+    }
+
+    #[test]
+    fn discontinuity_drops_partial_segment() -> io::Result<()> {
+        let memory = Memory::new();
+        let mut queued = queued(&memory)?;
+
+        queued.write_all(b"partial")?;
+        queued.discontinuity()?;
+        queued.write_all(b"complete")?;
+        queued.segment_stats(&SegmentStats::default())?;
+        drop(queued);
+
+        assert!(matches!(
+            memory.events().first(),
+            Some(Event::Discontinuity)
+        ));
+        assert_eq!(memory.segments(), [b"complete".to_vec()]);
+        Ok(())
+    }
+}
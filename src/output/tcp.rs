@@ -16,7 +16,7 @@ use log::{error, info};
 use super::Output;
 use crate::args::{Parse, Parser};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Args {
     addr: Option<SocketAddr>,
     client_timeout: Duration,
@@ -46,6 +46,12 @@ impl Parse for Args {
     }
 }
 
+impl Args {
+    pub const fn is_enabled(&self) -> bool {
+        self.addr.is_some()
+    }
+}
+
 pub struct Tcp {
     listener: TcpListener,
     client_timeout: Duration,
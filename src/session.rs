@@ -0,0 +1,436 @@
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    io, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use log::{debug, error, info};
+use pico_args::Arguments;
+
+use crate::{
+    args::{self, Parse, Parser},
+    heartbeat::{self, Heartbeat},
+    hls::{
+        Args as HlsArgs, Handler, OfflineError, Playlist, PreflightError, ResetError, Stream,
+        VideoFailedError,
+    },
+    http::{self, Agent, Method, StatusError, TimeoutError, Url, WriteError},
+    logger::{Color, Logger},
+    metrics,
+    output::{self, Output, Player, PlayerClosedError, RecordingFinishedError, Writer},
+};
+
+#[derive(Default, Debug)]
+pub struct Args {
+    debug: bool,
+    verbose: u8,
+    quiet: bool,
+    no_color: bool,
+    color: Color,
+    reconnect_attempts: u64,
+    heartbeat: heartbeat::Args,
+    metrics: Option<String>,
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse_switch_or(&mut self.debug, "-d", "--debug")?;
+        parser.parse_count(&mut self.verbose, "-vvvvvvvv", "verbose")?;
+        parser.parse_switch_or(&mut self.quiet, "-q", "--quiet")?;
+        parser.parse_switch(&mut self.no_color, "--no-color")?;
+        parser.parse_fn(&mut self.color, "--color", Color::new)?;
+        parser.parse(&mut self.reconnect_attempts, "--reconnect-attempts")?;
+        self.heartbeat.parse(parser)?;
+        parser.parse_opt(&mut self.metrics, "--metrics")?;
+
+        if self.no_color {
+            self.color = Color::Never;
+        }
+
+        ensure!(
+            !self.quiet || !self.debug && self.verbose == 0,
+            "--quiet cannot be used with -v or --debug"
+        );
+
+        Ok(())
+    }
+}
+
+fn main_loop(
+    mut writer: Writer,
+    mut playlist: Playlist,
+    agent: &Agent,
+    hls_args: &HlsArgs,
+    reconnect_attempts: u64,
+    mut heartbeat: Option<Heartbeat>,
+) -> Result<()> {
+    if let Some(url) = playlist.take_header() {
+        writer
+            .set_header(&fetch_header(&url, agent)?)
+            .context(WriteError)?;
+    }
+
+    if writer.should_wait() {
+        writer.wait_for_output().context(WriteError)?;
+    }
+
+    let mut handler = Handler::new(writer, agent, &hls_args.handler)?;
+    let mut hls_args = Cow::Borrowed(hls_args);
+    let mut reconnects = 0;
+    loop {
+        let time = Instant::now();
+
+        let Err(error) = process(&mut handler, &mut playlist, agent, time) else {
+            reconnects = 0;
+            metrics::set_edge_latency(playlist.edge_latency());
+            if let Some(heartbeat) = &mut heartbeat {
+                heartbeat.tick(
+                    handler.segments(),
+                    playlist.edge_latency(),
+                    handler.is_paused(),
+                );
+            }
+
+            continue;
+        };
+
+        if error.is::<ResetError>() {
+            playlist.reset();
+        } else if error.is::<VideoFailedError>() {
+            //Reconnecting would select the same quality again
+            if playlist.fall_back_to_audio()? {
+                hls_args.to_mut().fall_back_to_audio();
+            }
+        } else if error.is::<OfflineError>() {
+            handler.stream_ended()?;
+            return Err(error);
+        } else if reconnects < reconnect_attempts && http::is_transient_error(&error) {
+            reconnects += 1;
+            metrics::add_reconnect();
+
+            let delay = Duration::from_secs((1 << (reconnects - 1).min(5)).min(30));
+            error!(
+                "{error}, reconnecting in {}s ({reconnects}/{reconnect_attempts})...",
+                delay.as_secs(),
+            );
+            thread::sleep(delay);
+
+            match reconnect(&hls_args, agent) {
+                Ok(new) => {
+                    info!("Reconnected, resuming stream");
+                    playlist = new;
+
+                    //Errors from the previous session are stale at this point
+                    match handler.discontinuity() {
+                        Err(e) if !e.is::<ResetError>() && !http::is_transient_error(&e) => {
+                            return Err(e);
+                        }
+                        _ => (),
+                    }
+                }
+                Err(e) if http::is_transient_error(&e) => error!("Failed to reconnect: {e}"),
+                Err(e) => return Err(e),
+            }
+        } else {
+            return Err(error);
+        }
+    }
+}
+
+//Single snapshot of the playlist for --once, kept apart from the reload loop
+fn fetch_once(
+    mut writer: Writer,
+    mut playlist: Playlist,
+    agent: &Agent,
+    hls_args: &HlsArgs,
+) -> Result<()> {
+    if let Some(url) = playlist.take_header() {
+        writer
+            .set_header(&fetch_header(&url, agent)?)
+            .context(WriteError)?;
+    }
+
+    if writer.should_wait() {
+        writer.wait_for_output().context(WriteError)?;
+    }
+
+    Handler::new(writer, agent, &hls_args.handler)?.process_once(&mut playlist)
+}
+
+fn process(
+    handler: &mut Handler,
+    playlist: &mut Playlist,
+    agent: &Agent,
+    time: Instant,
+) -> Result<()> {
+    playlist.reload()?;
+    if let Some(url) = playlist.take_header() {
+        handler.set_header(fetch_header(&url, agent)?)?;
+    }
+
+    handler.process(playlist, time)
+}
+
+fn reconnect(hls_args: &HlsArgs, agent: &Agent) -> Result<Playlist> {
+    match Stream::new(hls_args.clone(), agent)? {
+        Stream::Variant(conn, variant) => Playlist::new(conn, variant, hls_args),
+        Stream::Passthrough(_) | Stream::Exit => bail!("Failed to resolve variant playlist"),
+    }
+}
+
+fn fetch_header(url: &Url, agent: &Agent) -> Result<Vec<u8>> {
+    let mut request = agent.binary(Vec::new());
+    request.call(Method::Get, url)?;
+
+    Ok(request.into_writer())
+}
+
+/// Options for a [`Session`], parsed from the same arguments and config file as the CLI.
+#[derive(Debug)]
+pub struct Config {
+    main: Args,
+    http: http::Args,
+    hls: HlsArgs,
+    output: output::Args,
+}
+
+impl Config {
+    /// Parses the process arguments exactly like the CLI does.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the arguments or the config file are invalid,
+    /// or [`HelpError`](crate::HelpError) for `-h`/`--help` and `-V`/`--version`.
+    pub fn from_env() -> Result<Self> {
+        Self::parse(Arguments::from_env())
+    }
+
+    /// Parses `args` as if they were passed on the command line, without the program name.
+    ///
+    /// Pass `--no-config` to ignore the user's config file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the arguments or the config file are invalid,
+    /// or [`HelpError`](crate::HelpError) for `-h`/`--help` and `-V`/`--version`.
+    pub fn from_args<I, S>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        Self::parse(Arguments::from_vec(
+            args.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// Installs the built-in stderr logger with the configured verbosity and colors.
+    ///
+    /// Optional, any other [`log`] implementation can be used instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a logger was already installed.
+    pub fn init_logger(&self) -> Result<()> {
+        Logger::init(
+            self.main.verbose.max(self.main.debug.into()),
+            self.main.quiet,
+            self.main.color,
+        )
+    }
+
+    fn parse(args: Arguments) -> Result<Self> {
+        let (main, http, hls, mut output) = args::parse(args)?;
+        output.player.quiet = main.quiet;
+
+        Ok(Self {
+            main,
+            http,
+            hls,
+            output,
+        })
+    }
+}
+
+/// A single run of the client: resolves the stream and writes it to every output until it ends.
+pub struct Session {
+    config: Config,
+}
+
+impl Session {
+    #[must_use]
+    pub const fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Runs until the stream ends, the player is closed or the recording is finished.
+    ///
+    /// `outputs` are written to alongside the outputs configured by arguments.
+    /// At least one output is required.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream is offline or fails in a way that can't be recovered from.
+    /// Use [`exit_code`] to classify it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the main loop returns without an error.
+    pub fn run(self, outputs: Vec<Box<dyn Output>>) -> Result<()> {
+        let Config {
+            main: main_args,
+            http: http_args,
+            hls: hls_args,
+            output: output_args,
+        } = self.config;
+        debug!("\n{main_args:#?}\n{http_args:#?}\n{hls_args:#?}\n{output_args:#?}");
+
+        if let Some(addr) = &main_args.metrics {
+            metrics::spawn(addr)?;
+        }
+
+        let agent = Agent::new(http_args)?;
+        let qualities = hls_args.split_qualities();
+        if qualities.len() == 1 {
+            return run_stream(&hls_args, &output_args, &main_args, &agent, outputs, None);
+        }
+
+        ensure!(
+            outputs.is_empty(),
+            "Multiple qualities cannot be used with custom outputs"
+        );
+        output_args.ensure_record_only()?;
+
+        //Each quality has its own playlist, worker and files
+        let results = thread::scope(|scope| -> Result<Vec<Result<()>>> {
+            let handles = qualities
+                .into_iter()
+                .enumerate()
+                .map(|(index, hls_args)| {
+                    let (main_args, output_args, agent) = (&main_args, &output_args, &agent);
+
+                    thread::Builder::new()
+                        .name(format!("stream {index}"))
+                        .spawn_scoped(scope, move || {
+                            run_stream(
+                                &hls_args,
+                                output_args,
+                                main_args,
+                                agent,
+                                Vec::new(),
+                                Some(index),
+                            )
+                        })
+                        .context("Failed to spawn stream thread")
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(handles
+                .into_iter()
+                .map(|h| h.join().expect("Stream thread panicked"))
+                .collect())
+        })?;
+
+        let mut errors = results.into_iter().filter_map(Result::err);
+        let first = errors.next();
+        for error in errors {
+            error!("{error}");
+        }
+
+        first.map_or(Ok(()), Err)
+    }
+}
+
+//Index is set when recording multiple qualities, to keep their files apart
+fn run_stream(
+    hls_args: &HlsArgs,
+    output_args: &output::Args,
+    main_args: &Args,
+    agent: &Agent,
+    outputs: Vec<Box<dyn Output>>,
+    index: Option<usize>,
+) -> Result<()> {
+    let (conn, variant) = match Stream::new(hls_args.clone(), agent) {
+        Ok(Stream::Variant(conn, variant)) => (conn, variant),
+        Ok(Stream::Passthrough(url)) => {
+            return Player::passthrough(&output_args.player, &url, &hls_args.channel);
+        }
+        Ok(Stream::Exit) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let name = match (index, &variant) {
+        (None, _) => hls_args.channel.clone(),
+        (Some(_), Some(variant)) => format!("{}_{}", hls_args.channel, variant.name()),
+        (Some(index), None) => format!("{}_{index}", hls_args.channel),
+    };
+
+    let writer = Writer::new(output_args, &name, outputs).context(WriteError)?;
+    let playlist = Playlist::new(conn, variant, hls_args)?;
+    if hls_args.preflight {
+        playlist.preflight(agent)?;
+    }
+
+    //Finished VODs are written like --once, there's nothing left to reload
+    let error = if hls_args.once || playlist.has_ended() {
+        match fetch_once(writer, playlist, agent, hls_args) {
+            Ok(()) => {
+                info!("Playlist written, exiting...");
+                return Ok(());
+            }
+            Err(e) => e,
+        }
+    } else {
+        main_loop(
+            writer,
+            playlist,
+            agent,
+            hls_args,
+            main_args.reconnect_attempts,
+            Heartbeat::new(&main_args.heartbeat),
+        )
+        .expect_err("Main loop returned Ok")
+    };
+    if error.is::<OfflineError>() {
+        info!("Stream ended, exiting...");
+        return Ok(());
+    }
+
+    if let Some(error) = error.downcast_ref::<io::Error>().and_then(|e| e.get_ref()) {
+        if error.is::<PlayerClosedError>() {
+            info!("Player closed, exiting...");
+            return Ok(());
+        }
+
+        if error.is::<RecordingFinishedError>() {
+            info!("Recording duration reached, exiting...");
+            return Ok(());
+        }
+    }
+
+    Err(error)
+}
+
+/// Maps an error from [`Session::run`] to the CLI's exit code.
+///
+/// - 1: any other error
+/// - 2: invalid arguments or config file, the CLI uses it for every error from [`Config`]
+/// - 3: the stream is offline
+/// - 4: network error
+/// - 5: output error
+/// - 6: `--preflight` check failed
+#[must_use]
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    if error.is::<PreflightError>() {
+        6
+    } else if error.is::<OfflineError>() {
+        3
+    } else if error.is::<WriteError>() {
+        5
+    } else if error.is::<StatusError>() || error.is::<TimeoutError>() || error.is::<io::Error>() {
+        4
+    } else {
+        1
+    }
+}
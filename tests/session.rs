@@ -0,0 +1,113 @@
+#![cfg(feature = "testing")]
+
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use anyhow::Result;
+use twitch_hls_client::{Config, Event, Memory, Session};
+
+//Every reload of /live.m3u8 adds a segment, it ends after the last one
+const LAST_SEGMENT: usize = 6;
+
+fn playlist(reloads: usize) -> String {
+    let newest = reloads.min(LAST_SEGMENT);
+
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n");
+    for sequence in 0..=newest {
+        _ = write!(playlist, "#EXTINF:0.010,live\n/s{sequence}\n");
+    }
+
+    if newest == LAST_SEGMENT {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+
+    playlist
+}
+
+fn respond(stream: TcpStream, reloads: &AtomicUsize) {
+    let mut writer = stream.try_clone().expect("Failed to clone stream");
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+
+        let mut line = String::new();
+        while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+            line.clear();
+        }
+
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let body = if path == "/live.m3u8" {
+            playlist(reloads.fetch_add(1, Ordering::SeqCst) + 1)
+        } else {
+            path.to_owned()
+        };
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn server() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+
+    let reloads = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let reloads = reloads.clone();
+            thread::spawn(move || respond(stream, &reloads));
+        }
+    });
+
+    Ok(port)
+}
+
+#[test]
+fn session_writes_segments_to_custom_output() -> Result<()> {
+    let port = server()?;
+    let config = Config::from_args([
+        "--no-config",
+        "--playlist-url",
+        &format!("http://127.0.0.1:{port}/live.m3u8"),
+        "--segment-deny",
+        "/s4$",
+    ])?;
+
+    let memory = Memory::new();
+    Session::new(config).run(vec![Box::new(memory.clone())])?;
+
+    //Joined at the newest segment, and the final playlist is still written
+    assert_eq!(
+        memory.segments(),
+        [b"/s3".to_vec(), b"/s5".into(), b"/s6".into()]
+    );
+
+    let events = memory.events();
+    let discontinuity = events
+        .iter()
+        .position(|e| matches!(e, Event::Discontinuity))
+        .expect("Missing discontinuity for the filtered segment");
+    let s5 = events
+        .iter()
+        .position(|e| matches!(e, Event::Write(data) if data == b"/s5"))
+        .expect("Missing segment after the filtered one");
+    assert!(discontinuity < s5);
+    assert!(matches!(events.last(), Some(Event::StreamEnded)));
+    Ok(())
+}